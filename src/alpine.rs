@@ -1,12 +1,14 @@
+use crate::cache::Cache;
+use crate::digest::hash_file;
+use crate::gpg;
 use crate::http;
 use crate::process::run_command_checked;
 use serde::Deserialize;
 use serde_yaml;
-use sha2::{Digest, Sha512};
+use sha2::Sha512;
 use std::ffi::OsStr;
-use std::fs::File;
-use std::io::{copy, Read, Result as IoResult};
-use std::path::Path;
+use std::io::Result as IoResult;
+use std::path::{Path, PathBuf};
 
 type Result<T> = core::result::Result<T, Error>;
 
@@ -16,18 +18,124 @@ macro_rules! err {
     }};
 }
 
+const CACHE_NAMESPACE: &str = "alpine";
+
+/// Repositories of the edge branch that packages are installed from
+const EDGE_REPOSITORIES: [&str; 3] = ["main", "community", "testing"];
+
+/// Release signing key of Natanael Copa, as published on the Alpine Linux website
+const SIGNING_KEY: &str = include_str!("../keys/ncopa.asc");
+const SIGNING_KEY_FINGERPRINT: &str = "0482D84022F52DF1C4E7CD43293ACD0907D9495A";
+
 pub struct BaseSystemDownloader {
     client: http::Client,
+    cache: Option<Cache>,
+    release: Release,
+    mirrors: Vec<String>,
+    signature: Option<SignatureVerification>,
+}
+
+/// OpenPGP verification of the tarball, with the built-in key unless one is given
+pub struct SignatureVerification {
+    key: Option<PathBuf>,
+    fingerprint: Option<String>,
+}
+
+impl SignatureVerification {
+    pub fn new(key: Option<PathBuf>, fingerprint: Option<String>) -> Self {
+        Self { key, fingerprint }
+    }
+
+    /// Checks that the signing key, written to `dir` if built in, has the expected fingerprint
+    pub fn check_key(&self, dir: &Path) -> Result<String> {
+        let (key_path, fingerprint) = self.key(&dir.join("ncopa.asc"))?;
+
+        let fingerprints = match gpg::key_fingerprints(&key_path) {
+            Ok(f) => f,
+            Err(e) => err!("invalid signing key: {}", e),
+        };
+
+        match fingerprint {
+            Some(f) if !fingerprints.contains(&f) => err!(
+                "signing key `{}` doesn't have the fingerprint `{}`",
+                key_path.display(),
+                f
+            ),
+            _ => Ok(fingerprints[0].to_owned()),
+        }
+    }
+
+    /// Returns the signing key and its expected fingerprint, writing the built-in key to `path`
+    fn key(&self, path: &Path) -> Result<(PathBuf, Option<String>)> {
+        if let Some(k) = &self.key {
+            return Ok((k.to_owned(), self.fingerprint.to_owned()));
+        }
+
+        if let Err(e) = std::fs::write(path, SIGNING_KEY) {
+            err!("failed to write the signing key: {}", e);
+        }
+
+        let fingerprint = match &self.fingerprint {
+            Some(f) => f.to_owned(),
+            None => SIGNING_KEY_FINGERPRINT.to_owned(),
+        };
+
+        Ok((path.to_owned(), Some(fingerprint)))
+    }
+}
+
+/// Identifies the Alpine release to download
+pub struct Release {
+    branch: String,
+    version: Option<String>,
+    sha512: Option<String>,
+}
+
+impl Release {
+    pub fn new(branch: Option<String>, version: Option<String>, sha512: Option<String>) -> Self {
+        // a pinned version implies its own release branch, e.g. `3.19.1` lives in `v3.19`
+        let branch = match (branch, &version) {
+            (Some(b), _) => b,
+            (None, Some(v)) => version_branch(v),
+            (None, None) => "latest-stable".to_owned(),
+        };
+
+        Self {
+            branch,
+            version,
+            sha512,
+        }
+    }
+}
+
+/// Describes where the base system tarball was obtained from
+pub enum Origin {
+    Cache,
+    Network,
+    /// Downloaded from the network, but storing it in the cache failed
+    NetworkUncached(Error),
 }
 
 impl BaseSystemDownloader {
-    pub fn new(client: http::Client) -> Self {
-        Self { client }
+    pub fn new(
+        client: http::Client,
+        cache: Option<Cache>,
+        release: Release,
+        mirrors: Vec<String>,
+        signature: Option<SignatureVerification>,
+    ) -> Self {
+        Self {
+            client,
+            cache,
+            release,
+            mirrors,
+            signature,
+        }
     }
 
-    pub fn download<P: AsRef<Path>>(&self, destination_path: P) -> Result<()> {
+    pub fn download<P: AsRef<Path>>(&self, destination_path: P) -> Result<Origin> {
         Ok(match self.download_impl(destination_path.as_ref()) {
-            Ok(_) => {}
+            Ok(o) => o,
             Err(e) => err!(
                 "unable to download and verify Alpine base system tarball: {}",
                 e
@@ -35,14 +143,184 @@ impl BaseSystemDownloader {
         })
     }
 
-    fn download_impl(&self, p: &Path) -> Result<()> {
+    fn download_impl(&self, p: &Path) -> Result<Origin> {
         let a = "x86_64";
+        let release_info = self.resolve_release(a)?;
+
+        if self.copy_from_cache(&release_info.sha512, p)? {
+            // the entry may have been cached with the verification disabled
+            self.verify_signature(a, &release_info, p)?;
+
+            return Ok(Origin::Cache);
+        }
+
+        // the size and checksum are computed while the tarball is being downloaded
+        let download = self.download_tarball(a, &release_info.file, p)?;
+        if let Some(size) = release_info.size {
+            verify_tarball_size(download.size(), size)?;
+        }
+        verify_downloaded_checksum(download.digest(), &release_info.sha512)?;
+
+        // the checksum comes from the same server as the tarball, so only the
+        // signature proves that the tarball was released by Alpine developers
+        self.verify_signature(a, &release_info, p)?;
+
+        Ok(self.store_in_cache(&release_info.sha512, p))
+    }
+
+    /// Verifies the signature of the `p` tarball, caching it to verify cache hits offline
+    fn verify_signature(&self, a: &str, release_info: &VersionFile, p: &Path) -> Result<()> {
+        let verification = match &self.signature {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let signature_path = sibling_path(p, ".asc");
+        let key = format!("{}.asc", cache_key(&release_info.sha512));
+        let cached = self
+            .cache
+            .as_ref()
+            .and_then(|c| c.lookup(CACHE_NAMESPACE, &key));
+
+        match &cached {
+            Some(c) => {
+                if let Err(e) = std::fs::copy(c, &signature_path) {
+                    err!(
+                        "failed to copy the cached signature `{}`: {}",
+                        c.display(),
+                        e
+                    );
+                }
+            }
+            None => {
+                let path = format!(
+                    "{}/releases/{}/{}.asc",
+                    self.release.branch, a, release_info.file
+                );
+                if let Err(e) = self.download_file(&path, &signature_path) {
+                    err!("failed to download the tarball signature: {}", e);
+                }
+            }
+        }
+
+        let (key_path, fingerprint) = verification.key(&sibling_path(p, ".key.asc"))?;
+
+        let result = gpg::verify_detached(&key_path, &signature_path, p, fingerprint.as_deref());
+
+        // the tarball is still verified next time if the signature isn't cached
+        if let (Ok(_), None, Some(c)) = (&result, &cached, &self.cache) {
+            let _ = c.store(CACHE_NAMESPACE, &key, &signature_path);
+        }
+
+        let _ = std::fs::remove_file(&signature_path);
+        if verification.key.is_none() {
+            let _ = std::fs::remove_file(&key_path);
+        }
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => err!("signature verification failed: {}", e),
+        }
+    }
+
+    fn download_file(&self, path: &str, destination: &Path) -> http::Result<http::Download> {
+        let req = http::GetRequest::mirrored(&self.mirrors, path)?;
+
+        self.client.download::<Sha512>(req, destination)
+    }
+
+    fn resolve_release(&self, a: &str) -> Result<VersionFile> {
+        let r = &self.release;
+
+        // a fully pinned release doesn't need the version file at all
+        if let (Some(version), Some(sha512)) = (&r.version, &r.sha512) {
+            return Ok(VersionFile {
+                flavor: "alpine-minirootfs".to_owned(),
+                version: version.to_owned(),
+                file: format!("alpine-minirootfs-{}-{}.tar.gz", version, a),
+                size: None,
+                sha512: sha512.to_owned(),
+            });
+        }
+
         let version_file = self.download_version_file(a)?;
         let release_info = parse_release_info(&version_file)?;
-        let downloaded_size = self.download_tarball(a, &release_info.file, p)?;
-        verify_tarball_size(downloaded_size, release_info.size)?;
-        verify_checksum(p, &release_info.sha512)?;
-        Ok({})
+
+        if let Some(v) = &r.version {
+            if &release_info.version != v {
+                err!(
+                    "pinned version `{}` is not the latest `{}` release of the `{}` branch, \
+                    set the `alpine.sha512` option to use an older release",
+                    v,
+                    release_info.version,
+                    r.branch
+                );
+            }
+        }
+
+        if let Some(c) = &r.sha512 {
+            if &release_info.sha512 != c {
+                err!(
+                    "pinned SHA-512 checksum doesn't match the latest `{}` release `{}` of the `{}` branch",
+                    release_info.file,
+                    release_info.version,
+                    r.branch
+                );
+            }
+        }
+
+        Ok(release_info)
+    }
+
+    fn copy_from_cache(&self, sha512: &str, p: &Path) -> Result<bool> {
+        let cache = match &self.cache {
+            Some(c) => c,
+            None => return Ok(false),
+        };
+
+        let key = cache_key(sha512);
+        let entry = match cache.lookup(CACHE_NAMESPACE, &key) {
+            Some(e) => e,
+            None => return Ok(false),
+        };
+
+        // a corrupted cache entry is discarded and downloaded again
+        if verify_checksum(&entry, sha512).is_err() {
+            if let Err(e) = cache.evict(CACHE_NAMESPACE, &key) {
+                err!(
+                    "failed to remove the corrupted cache entry `{}`: {}",
+                    entry.display(),
+                    e
+                );
+            }
+
+            return Ok(false);
+        }
+
+        if let Err(e) = std::fs::copy(&entry, p) {
+            err!(
+                "failed to copy the cached tarball `{}` to `{}`: {}",
+                entry.display(),
+                p.display(),
+                e
+            );
+        }
+
+        Ok(true)
+    }
+
+    fn store_in_cache(&self, sha512: &str, p: &Path) -> Origin {
+        let cache = match &self.cache {
+            Some(c) => c,
+            None => return Origin::Network,
+        };
+
+        match cache.store(CACHE_NAMESPACE, &cache_key(sha512), p) {
+            Ok(_) => Origin::Network,
+            Err(e) => {
+                Origin::NetworkUncached(Error::new(format!("failed to cache the tarball: {}", e)))
+            }
+        }
     }
 
     fn download_version_file(&self, a: &str) -> Result<String> {
@@ -53,47 +331,40 @@ impl BaseSystemDownloader {
     }
 
     fn download_verion_file_impl(&self, a: &str) -> http::Result<String> {
-        let url = format!(
-            "https://dl-cdn.alpinelinux.org/alpine/latest-stable/releases/{}/latest-releases.yaml",
-            a
+        let path = format!(
+            "{}/releases/{}/latest-releases.yaml",
+            self.release.branch, a
         );
 
-        let req = http::GetRequest::new(url)?;
+        let req = http::GetRequest::mirrored(&self.mirrors, &path)?;
         let response = self.client.get(req)?.as_text()?;
 
         Ok(response)
     }
 
-    fn download_tarball(&self, a: &str, t: &str, p: &Path) -> Result<u64> {
-        let reader = match self.download_tarball_impl(a, t) {
-            Ok(r) => r,
+    fn download_tarball(&self, a: &str, t: &str, p: &Path) -> Result<http::Download> {
+        Ok(match self.download_tarball_impl(a, t, p) {
+            Ok(d) => d,
             Err(e) => err!("download failed: {}", e),
-        };
-
-        Ok(match write_tarball(reader, p) {
-            Ok(s) => s,
-            Err(e) => err!("failed to write tarball file: {}", e),
         })
     }
 
-    fn download_tarball_impl(&self, a: &str, t: &str) -> http::Result<impl Read> {
-        let url = format!(
-            "https://dl-cdn.alpinelinux.org/alpine/latest-stable/releases/{}/{}",
-            a, t
-        );
+    fn download_tarball_impl(&self, a: &str, t: &str, p: &Path) -> http::Result<http::Download> {
+        let path = format!("{}/releases/{}/{}", self.release.branch, a, t);
 
-        let req = http::GetRequest::new(url)?;
-        let response = self.client.get(req)?.as_reader()?;
+        let req = http::GetRequest::mirrored(&self.mirrors, &path)?;
+        let download = self.client.download::<Sha512>(req, p)?;
 
-        Ok(response)
+        Ok(download)
     }
 }
 
 #[derive(Deserialize)]
 struct VersionFile {
     flavor: String,
+    version: String,
     file: String,
-    size: u64,
+    size: Option<u64>,
     sha512: String,
 }
 
@@ -133,7 +404,24 @@ fn parse_release_info(f: &str) -> Result<VersionFile> {
     err!("unable to find the `alpine-minirootfs` release in the version file")
 }
 
-fn verify_checksum(p: &Path, c: &str) -> Result<()> {
+fn sibling_path(p: &Path, suffix: &str) -> PathBuf {
+    let mut name = p.file_name().unwrap_or_default().to_owned();
+    name.push(suffix);
+
+    p.with_file_name(name)
+}
+
+fn version_branch(version: &str) -> String {
+    let parts: Vec<&str> = version.split('.').take(2).collect();
+
+    format!("v{}", parts.join("."))
+}
+
+fn cache_key(sha512: &str) -> String {
+    format!("{}.tar.gz", sha512)
+}
+
+pub fn verify_checksum(p: &Path, c: &str) -> Result<()> {
     match verify_checksum_impl(p, c) {
         Ok(_) => Ok({}),
         Err(e) => err!("checksum verification failed: {}", e),
@@ -141,11 +429,7 @@ fn verify_checksum(p: &Path, c: &str) -> Result<()> {
 }
 
 fn verify_checksum_impl(p: &Path, c: &str) -> IoResult<()> {
-    let mut hasher = Sha512::new();
-    let mut file = File::open(p)?;
-
-    copy(&mut file, &mut hasher)?;
-    let actual_checksum = format!("{:x}", hasher.finalize());
+    let (_, actual_checksum) = hash_file::<Sha512>(p)?;
 
     if actual_checksum == c {
         return Ok({});
@@ -172,17 +456,25 @@ fn verify_tarball_size(download_size: u64, expected_size: u64) -> Result<()> {
     )
 }
 
-fn write_tarball(r: impl Read, p: &Path) -> IoResult<u64> {
-    let mut r = r;
-    let mut file = File::create(p)?;
-    copy(&mut r, &mut file)
+fn verify_downloaded_checksum(actual_checksum: &str, c: &str) -> Result<()> {
+    if actual_checksum == c {
+        return Ok(());
+    }
+
+    err!(
+        "checksum verification failed: SHA-512 checksum doesn't match. Expected `{}`, got `{}`",
+        c,
+        actual_checksum
+    )
 }
 
-pub fn enable_edge_repositories() -> Result<()> {
+pub fn enable_edge_repositories<M: AsRef<str>>(mirror: M) -> Result<()> {
     let repository_conf_path = "/etc/apk/repositories";
-    let repositories = "https://dl-cdn.alpinelinux.org/alpine/edge/main/\n\
-        https://dl-cdn.alpinelinux.org/alpine/edge/community/\n\
-        https://dl-cdn.alpinelinux.org/alpine/edge/testing/\n";
+    let mirror = mirror.as_ref().trim_end_matches('/');
+    let repositories: String = EDGE_REPOSITORIES
+        .iter()
+        .map(|r| format!("{}/edge/{}/\n", mirror, r))
+        .collect();
 
     if let Err(e) = std::fs::write(repository_conf_path, repositories) {
         err!("failed to enable edge repositories: {}", e);
@@ -214,3 +506,46 @@ pub fn install_packages(packages: &[&str]) -> Result<()> {
         Err(e) => err!("failed to install packages: {}", e),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use tempdir::TempDir;
+
+    #[test]
+    fn built_in_signing_key_has_expected_fingerprint() {
+        let home = TempDir::new("nixosconj-test-gnupg").unwrap();
+        let key_path = home.path().join("ncopa.asc");
+        std::fs::write(&key_path, SIGNING_KEY).unwrap();
+
+        let gpg = |args: &[&OsStr]| {
+            Command::new("gpg")
+                .args([OsStr::new("--batch"), OsStr::new("--homedir")])
+                .arg(home.path())
+                .args(args)
+                .output()
+                .unwrap()
+        };
+
+        let import = gpg(&[OsStr::new("--import"), key_path.as_os_str()]);
+        assert!(import.status.success(), "{:?}", import);
+
+        // fpr:::::::::<fingerprint>: follows the primary key record
+        let list = gpg(&[OsStr::new("--with-colons"), OsStr::new("--fingerprint")]);
+        let listing = String::from_utf8_lossy(&list.stdout);
+        let fingerprint = listing
+            .lines()
+            .find_map(|l| l.strip_prefix("fpr:"))
+            .and_then(|l| l.split(':').nth(8));
+
+        assert_eq!(fingerprint, Some(SIGNING_KEY_FINGERPRINT));
+    }
+
+    #[test]
+    fn version_branch_keeps_major_and_minor_version() {
+        assert_eq!(version_branch("3.19.1"), "v3.19");
+        assert_eq!(version_branch("3.20.0_rc1"), "v3.20");
+        assert_eq!(version_branch("3.18"), "v3.18");
+    }
+}
@@ -1,13 +1,23 @@
 use crate::{
-    alpine::BaseSystemDownloader,
-    builder::{self, Builder},
+    alpine::{BaseSystemDownloader, Release, SignatureVerification},
+    builder::{self, BuildDir, Builder},
+    cache::Cache,
+    cli::{Arguments, Command},
     config::Configuration,
+    doctor, gpg,
     http::Client,
-    process::run_forked,
+    limits::{check_disk_usage, DISK_CHECK_INTERVAL},
+    log::{self, Event, Format, Level},
+    process::{
+        install_signal_handlers, run_forked, run_forked_watched, run_init, set_environment,
+        set_timeouts, set_verbosity, Verbosity,
+    },
 };
+use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
 pub struct App {
+    command: Command,
     builder: Builder,
 }
 
@@ -45,21 +55,60 @@ impl std::fmt::Display for Error {
 
 impl std::error::Error for Error {}
 
-pub fn init_app(args: &Vec<String>) -> Result<App, Error> {
-    let conf_path = parse_arguments(args)?;
-    let configuration = match Configuration::load(&conf_path) {
-        Ok(c) => c,
-        Err(e) => {
-            return Err(Error::new(
-                ErrorCode::ConfigurationLoaderError,
-                format!("{}", e),
-            ))
-        }
+pub fn init_app(arguments: &Arguments) -> Result<App, Error> {
+    let mut configuration = match arguments.configuration_path() {
+        Some(p) => match Configuration::load(p) {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(Error::new(
+                    ErrorCode::ConfigurationLoaderError,
+                    format!("{}", e),
+                ))
+            }
+        },
+        None => Configuration::default(),
     };
 
+    apply_overrides(&mut configuration, arguments);
+
+    // the defaults are valid as well, but the overrides may not be
+    let mut result = configuration.validate();
+    if result.is_ok() && arguments.command().requires_output_formats() {
+        result = configuration.validate_output_formats();
+    }
+
+    if let Err(e) = result {
+        return Err(Error::new(
+            ErrorCode::ConfigurationLoaderError,
+            format!("{}", e),
+        ));
+    }
+
+    log::set_format(Format::from_name(configuration.log_format()).unwrap_or(Format::Text));
+    set_verbosity(match (configuration.verbose(), configuration.quiet()) {
+        (true, _) => Verbosity::Verbose,
+        (_, true) => Verbosity::Quiet,
+        _ => Verbosity::Normal,
+    });
+
+    // commands in the build environment must reach the network the same way
+    let mut environment = configuration.http().proxy_environment();
+    if configuration.http().ca_bundle().is_some() {
+        for name in ["SSL_CERT_FILE", "NIX_SSL_CERT_FILE"] {
+            environment.push((name.to_owned(), builder::CA_BUNDLE_PATH.to_owned()));
+        }
+    }
+    set_environment(environment);
+
+    let http = configuration.http();
     let client = match Client::builder()
-        .connect_timeout(None)
-        .request_timeout(None)
+        .connect_timeout(http.connect_timeout())
+        .request_timeout(http.request_timeout())
+        .retries(http.retries())
+        .retry_backoff(http.retry_backoff())
+        .https_proxy(http.https_proxy().clone())
+        .no_proxy(http.no_proxy().clone())
+        .ca_bundle(http.ca_bundle().clone())
         .build()
     {
         Ok(c) => c,
@@ -71,66 +120,305 @@ pub fn init_app(args: &Vec<String>) -> Result<App, Error> {
         }
     };
 
-    let bsd = BaseSystemDownloader::new(client);
-    let builder = Builder::new(bsd, configuration);
+    let cache_dir = match configuration.cache_dir() {
+        Some(d) => Some(d.to_owned()),
+        None => Cache::default_root(),
+    };
+
+    let alpine = configuration.alpine();
+    let release = Release::new(
+        alpine.branch().clone(),
+        alpine.version().clone(),
+        alpine.sha512(),
+    );
 
-    Ok(App { builder })
+    let signature = match alpine.verify_signature() {
+        true => Some(SignatureVerification::new(
+            alpine.signing_key().clone(),
+            alpine
+                .signing_key_fingerprint()
+                .as_deref()
+                .map(gpg::normalize_fingerprint),
+        )),
+        false => None,
+    };
+
+    let bsd = BaseSystemDownloader::new(
+        client.clone(),
+        cache_dir.clone().map(Cache::new),
+        release,
+        configuration.mirrors().alpine(),
+        signature,
+    );
+    let builder = Builder::new(bsd, client, cache_dir.map(Cache::new), configuration);
+
+    Ok(App {
+        command: arguments.command(),
+        builder,
+    })
 }
 
-fn parse_arguments(args: &Vec<String>) -> Result<PathBuf, Error> {
-    if args.len() != 2 {
-        return Err(Error::new(
-            ErrorCode::CommandLineParserError,
-            "Failed to parse command line arguments.",
-        ));
+fn apply_overrides(configuration: &mut Configuration, arguments: &Arguments) {
+    if arguments.verbose() {
+        configuration.set_verbose(true);
+        configuration.set_quiet(false);
+    }
+
+    if arguments.quiet() {
+        configuration.set_quiet(true);
+        configuration.set_verbose(false);
     }
 
-    Ok(PathBuf::from(&args[1]))
+    if let Some(p) = arguments.output_path() {
+        configuration.set_output_path(p);
+    }
+
+    if let Some(p) = arguments.temporary_dir() {
+        configuration.set_temporary_dir(p);
+    }
+
+    if let Some(f) = arguments.log_format() {
+        configuration.set_log_format(f.to_owned());
+    }
+
+    if !arguments.output_formats().is_empty() {
+        configuration.set_output_formats(arguments.output_formats().clone());
+    }
 }
 
 impl App {
     pub fn run(&self) -> Result<(), Error> {
-        match self.run_build() {
-            Err(_) => return Err(Error::new(ErrorCode::RuntimeError, "Build failed.")),
-            _ => Ok({}),
+        let timeouts = self.builder.configuration().timeouts();
+        if let Err(e) = set_timeouts(timeouts.overall(), timeouts.stages()) {
+            return Err(Error::new(ErrorCode::InitializtionError, format!("{}", e)));
+        }
+
+        // SIGINT belongs to the interactive shell, while it is running
+        if let Err(e) = install_signal_handlers(self.command != Command::Shell) {
+            return Err(Error::new(ErrorCode::InitializtionError, format!("{}", e)));
+        }
+
+        let log_path = match self.command {
+            Command::Build => Some(self.open_log_file()?),
+            _ => None,
+        };
+
+        let result = match self.command {
+            Command::Build => self.run_build(),
+            Command::Validate => self.run_validate(),
+            Command::Shell => self.run_shell(),
+            Command::Clean => self.builder.clean_build_directories(),
+            Command::Formats => self.run_formats(),
+            Command::Doctor => doctor::run(self.builder.configuration()),
+            Command::Gc => self.run_gc(),
+            Command::Help => Ok(()),
+        };
+
+        log::finish_stage(result.is_ok());
+        log::event(Event::Result {
+            command: self.command.name(),
+            success: result.is_ok(),
+            log_file: log_path.as_deref(),
+        });
+
+        if result.is_ok() {
+            return Ok(());
         }
+
+        let message = match log_path {
+            Some(p) => format!(
+                "Command `{}` failed, see `{}` for the full log.",
+                self.command.name(),
+                p.display()
+            ),
+            None => format!("Command `{}` failed.", self.command.name()),
+        };
+        log::write(&message);
+
+        Err(Error::new(ErrorCode::RuntimeError, message))
+    }
+
+    /// Opens the log file in the `log_dir` directory, or next to the output image
+    fn open_log_file(&self) -> Result<PathBuf, Error> {
+        let conf = self.builder.configuration();
+        let dir = match (conf.log_dir(), conf.output_path()) {
+            (Some(d), _) => d.to_owned(),
+            (None, Some(p)) if conf.output_formats().len() > 1 => p.to_owned(),
+            (None, Some(p)) => match p.parent() {
+                Some(d) if d != Path::new("") => d.to_owned(),
+                _ => PathBuf::from("."),
+            },
+            (None, None) => PathBuf::from("."),
+        };
+
+        match log::open_file(&dir) {
+            Ok(p) => {
+                log::info(&format!("Writing the build log to `{}`...", p.display()));
+
+                Ok(p)
+            }
+            Err(e) => Err(Error::new(
+                ErrorCode::InitializtionError,
+                format!("Failed to create a log file in `{}`: {}", dir.display(), e),
+            )),
+        }
+    }
+
+    /// Bootstraps the build environment on its own when it is to be snapshotted
+    fn bootstrap(&self, build_dir: &BuildDir) -> Result<(), ()> {
+        let snapshot = match build_dir.snapshot() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+
+        run_in_namespace(build_dir.path(), self.builder.configuration(), || {
+            self.builder.run_bootstrap_process()
+        })?;
+
+        self.builder.store_snapshot(build_dir.path(), snapshot);
+
+        Ok(())
+    }
+
+    fn run_validate(&self) -> Result<(), ()> {
+        log::info("The configuration is valid.");
+
+        Ok(())
     }
 
     fn run_build(&self) -> Result<(), ()> {
         // Prepare chroot environment
         let build_dir = self.builder.create_chroot()?;
+        self.bootstrap(&build_dir)?;
 
         // Run the build process in an isolated chroot environment
-        let image_path = match run_forked(|| self.build(build_dir.path())) {
-            Ok(r) => r?,
-            Err(e) => {
-                eprintln!("!!! FAILURE: {}", e);
+        let artifacts = run_in_namespace(build_dir.path(), self.builder.configuration(), || {
+            self.builder.run_build_process()
+        })?;
+
+        // Pull the images out of temporary root directory
+        for artifact in &artifacts {
+            self.builder.pull_image(artifact, build_dir.path())?;
+        }
 
-                return Err({});
+        Ok({})
+    }
+
+    fn run_shell(&self) -> Result<(), ()> {
+        // Prepare chroot environment
+        let build_dir = self.builder.create_chroot()?;
+        self.bootstrap(&build_dir)?;
+
+        // Run the interactive shell in an isolated chroot environment
+        run_in_namespace(build_dir.path(), self.builder.configuration(), || {
+            self.builder.run_shell_process()
+        })
+    }
+
+    fn run_gc(&self) -> Result<(), ()> {
+        let max_size = match self.builder.configuration().nix_store() {
+            Some(s) => s.max_size(),
+            None => {
+                log::message(
+                    Level::Failure,
+                    "the `gc` command requires the `nix_store` option",
+                );
+
+                return Err(());
             }
         };
 
-        // Pull the image out of temporary root directory
-        self.builder.pull_image(&image_path)?;
+        // Prepare chroot environment, which locks the store as well
+        let build_dir = self.builder.create_gc_chroot()?;
 
-        Ok({})
+        let size = self.builder.measure_nix_store()?;
+        let max_freed = match max_size {
+            Some(m) if size <= m => {
+                log::info("The Nix store doesn't exceed `nix_store.max_size`.");
+
+                return Ok(());
+            }
+            Some(m) => Some(size - m),
+            None => None,
+        };
+
+        // Run the garbage collector in an isolated chroot environment
+        run_in_namespace(build_dir.path(), self.builder.configuration(), || {
+            self.builder.run_gc_process(max_freed)
+        })?;
+
+        self.builder.measure_nix_store()?;
+
+        Ok(())
     }
 
-    fn build(&self, root_path: &Path) -> Result<PathBuf, ()> {
+    fn run_formats(&self) -> Result<(), ()> {
+        // Prepare chroot environment
+        let build_dir = self.builder.create_chroot()?;
+        self.bootstrap(&build_dir)?;
+
+        // Ask `nixos-generate` for the list of formats it supports
+        let formats = run_in_namespace(build_dir.path(), self.builder.configuration(), || {
+            self.builder.run_formats_process()
+        })?;
+
+        // one message per format keeps the standard output valid in JSON mode
+        for format in formats.lines() {
+            log::info(format);
+        }
+
+        Ok(())
+    }
+}
+
+fn run_in_namespace<T: Serialize + for<'de> Deserialize<'de>, F: Fn() -> Result<T, ()>>(
+    root_path: &Path,
+    conf: &Configuration,
+    f: F,
+) -> Result<T, ()> {
+    let limits = conf.limits();
+    let sandbox = conf.sandbox();
+    let nix_store = conf.nix_store().as_ref().map(|s| s.path());
+
+    // The cgroup is removed once every process in it is gone
+    let cgroup = builder::create_cgroup(limits);
+
+    let build = || {
+        // Limit the resources before the namespace hides the cgroup tree
+        builder::enter_limits(limits, cgroup.as_ref())?;
+
         // Create a new namespace for the build process
-        builder::setup_namespace(root_path)?;
+        builder::setup_namespace(root_path, sandbox, nix_store)?;
 
-        // Run the build process in the new namespace
-        let image_path = match run_forked(|| self.builder.run_build_process()) {
-            Ok(p) => p?,
-            Err(e) => {
-                eprintln!("!!! FAILURE: {}", e);
+        // Run the build process in the new namespace, under a minimal init
+        let init = || match builder::setup_pid_namespace(sandbox) {
+            Ok(_) => run_init(&f),
+            Err(_) => Ok(Err(())),
+        };
+        match run_forked(init) {
+            Ok(Ok(r)) => r,
+            Ok(Err(e)) | Err(e) => {
+                log::message(Level::Failure, &format!("{}", e));
 
-                return Err({});
+                Err(())
             }
-        };
+        }
+    };
+
+    // The disk limit is enforced by measuring the build root as it grows
+    let result = match limits.disk() {
+        Some(d) => run_forked_watched(build, DISK_CHECK_INTERVAL, || {
+            check_disk_usage(root_path, d)
+        }),
+        None => run_forked(build),
+    };
 
-        // Return the resulting absolute path to the built image
-        Ok(root_path.join(&image_path))
+    match result {
+        Ok(r) => r,
+        Err(e) => {
+            log::message(Level::Failure, &format!("{}", e));
+
+            Err(())
+        }
     }
 }
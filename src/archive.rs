@@ -1,8 +1,33 @@
+use crate::process::{current_deadline, is_cancelled, Deadline};
+
 pub fn extract<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<()> {
     let file = std::fs::File::open(&path)?;
-    let decoder = flate2::read::GzDecoder::new(file);
+    let decoder = flate2::read::GzDecoder::new(Interruptible {
+        inner: file,
+        deadline: current_deadline(),
+    });
     let mut archive = tar::Archive::new(decoder);
 
     let dst = path.as_ref().parent().unwrap();
     archive.unpack(dst)
 }
+
+/// Reader that fails once the run is cancelled or out of time
+struct Interruptible<R> {
+    inner: R,
+    deadline: Option<Deadline>,
+}
+
+impl<R: std::io::Read> std::io::Read for Interruptible<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if is_cancelled() {
+            return Err(std::io::Error::other("the run was cancelled"));
+        }
+
+        if let Some(d) = self.deadline.as_ref().filter(|d| d.is_expired()) {
+            return Err(std::io::Error::other(d.error("the extraction")));
+        }
+
+        self.inner.read(buf)
+    }
+}
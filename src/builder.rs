@@ -1,116 +1,720 @@
 use crate::{
-    alpine::{self, BaseSystemDownloader},
+    alpine::{self, BaseSystemDownloader, Origin},
     archive::extract,
-    config::Configuration,
+    cache::Cache,
+    config::{Configuration, DnsMode, LimitsConfiguration, SandboxConfiguration},
+    digest::hash_file,
+    http,
+    limits::{self, Cgroup},
+    lock::{DirectoryLock, LOCK_FILE},
+    log::{self, Event, Level},
     mount, nixos,
-    process::run_command_checked,
+    process::{self, run_command_checked, run_host_command_checked, run_interactive},
+    store,
 };
 use nix::{
+    mount::{umount2, MntFlags, MsFlags},
     sched::{unshare, CloneFlags},
     unistd::{chroot, getgid, getuid, pivot_root},
 };
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
 use std::{
     env::set_current_dir,
-    ffi::OsString,
-    fs::{copy, set_permissions, File, Permissions},
+    ffi::{OsStr, OsString},
+    fs::{copy, create_dir, set_permissions, File, OpenOptions, Permissions},
     io::Write,
-    os::unix::fs::PermissionsExt,
-    os::unix::prelude::OsStringExt,
+    os::unix::fs::{symlink, PermissionsExt},
+    os::unix::prelude::{OsStrExt, OsStringExt},
     path::{Path, PathBuf},
 };
 use tempdir::TempDir;
 use walkdir::WalkDir;
 
-macro_rules! ok {
-    ($($msg:expr),+) => {{
-        print!("... OK: ");
-        println!($($msg),+);
+const BUILD_DIR_PREFIX: &str = "nixosconj";
+
+/// Name of the downloaded base system tarball in the build root
+const ROOTFS_TARBALL: &str = "alpine-minirootfs.tgz";
+
+/// Cache namespace of the bootstrapped build root snapshots
+const SNAPSHOT_NAMESPACE: &str = "snapshots";
+
+/// Number of the most recently used snapshots kept once a new one is stored
+const SNAPSHOTS_KEPT: usize = 3;
+
+/// Lists the mount points created in the build root, one relative path per line
+const MOUNT_POINTS_FILE: &str = ".nixos-conjurer.mount-points";
+
+/// Created inside the build root once everything `nixos-generate` needs is installed
+const BOOTSTRAP_MARKER: &str = "/.bootstrapped";
+
+/// Location of the system CA bundle inside the build root
+pub const CA_BUNDLE_PATH: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+/// Location of the pinned nixpkgs tarball inside the build root
+const NIXPKGS_TARBALL: &str = "/nixpkgs.tar.gz";
+
+/// Location of the copied Nix configuration directory inside the build root
+const CONFIGURATION_DIR_PATH: &str = "/configuration";
+
+/// Location of the copied Nix flake inside the build root
+const FLAKE_PATH: &str = "/flake";
+
+/// Name of the channel that `nixos-generators` and `<nixpkgs>` come from
+const NIXPKGS_CHANNEL_NAME: &str = "nixpkgs";
+
+/// Address of the systemd-resolved stub resolver
+const RESOLVED_STUB_ADDRESS: &str = "127.0.0.53";
+
+/// Upstream servers used by systemd-resolved, in the `resolv.conf` format
+const RESOLVED_UPSTREAM_RESOLV_CONF: &str = "/run/systemd/resolve/resolv.conf";
+
+/// Names of the stages reported by the `stage!` macro
+pub const STAGES: &[&str] = &[
+    "lock_nix_store",
+    "create_build_dir",
+    "write_configuration",
+    "copy_configuration_dir",
+    "copy_flake",
+    "download_base_system",
+    "copy_base_system",
+    "lookup_snapshot",
+    "restore_snapshot",
+    "extract_base_system",
+    "fix_dns",
+    "install_ca_bundle",
+    "download_nixpkgs",
+    "apply_limits",
+    "enter_namespace",
+    "add_repositories",
+    "install_nix",
+    "update_channels",
+    "install_nixos_generators",
+    "store_snapshot",
+    "generate_image",
+    "pull_image",
+    "shell",
+    "list_formats",
+    "measure_nix_store",
+    "collect_garbage",
+    "clean_build_dirs",
+];
+
+/// Stages that run commands, transfer data or fork and so can be given a time limit
+pub const TIMED_STAGES: &[&str] = &[
+    "lock_nix_store",
+    "download_base_system",
+    "lookup_snapshot",
+    "restore_snapshot",
+    "extract_base_system",
+    "download_nixpkgs",
+    "enter_namespace",
+    "add_repositories",
+    "install_nix",
+    "update_channels",
+    "install_nixos_generators",
+    "store_snapshot",
+    "generate_image",
+    "shell",
+    "list_formats",
+    "measure_nix_store",
+    "collect_garbage",
+];
+
+macro_rules! stage {
+    ($name:expr, $($msg:expr),+) => {{
+        log::stage($name, &format!($($msg),+));
+        if let Err(e) = process::enter_stage($name) {
+            err!("{}", e);
+        }
+    }};
+    // best-effort stages warn and return `$fallback` once the run is over time
+    (else $($fallback:expr)?; $name:expr, $($msg:expr),+) => {{
+        log::stage($name, &format!($($msg),+));
+        if let Err(e) = process::enter_stage($name) {
+            log::message(Level::Warning, &format!("{}", e));
+            log::finish_stage(false);
+
+            return $($fallback)?;
+        }
     }}
 }
 
+macro_rules! ok {
+    ($($msg:expr),+) => {
+        log::message(Level::Ok, &format!($($msg),+))
+    }
+}
+
 macro_rules! err {
     ($($msg:expr),+) => {{
-        eprint!("... ERROR: ");
-        eprintln!($($msg),+);
+        log::message(Level::Error, &format!($($msg),+));
+        log::finish_stage(false);
 
         return Err({})
     }};
 }
 
 pub struct BuildDir {
-    dir: TempDir,
+    dir: Option<TempDir>,
+    snapshot: Option<PathBuf>,
+    store_lock: Option<DirectoryLock>,
+    /// Keeps `clean` away from the directory while it's in use
+    _lock: DirectoryLock,
 }
 
 impl BuildDir {
-    pub fn new(dir: TempDir) -> Self {
-        Self { dir }
+    pub fn new(dir: TempDir, lock: DirectoryLock) -> Self {
+        Self {
+            dir: Some(dir),
+            snapshot: None,
+            store_lock: None,
+            _lock: lock,
+        }
     }
 
     pub fn path(&self) -> &Path {
-        self.dir.path()
+        self.dir.as_ref().unwrap().path()
+    }
+
+    /// Location of the snapshot to store once the build root is bootstrapped
+    pub fn snapshot(&self) -> Option<&Path> {
+        self.snapshot.as_deref()
     }
 }
 
 impl Drop for BuildDir {
     fn drop(&mut self) {
-        // Fix permissions so that a directory can be automatically removed
-        fix_permissions(self.dir.path());
+        let dir = match self.dir.take() {
+            Some(d) => d,
+            None => return,
+        };
+
+        // Fix permissions so that a directory can be removed
+        fix_permissions(dir.path());
+
+        let path = dir.path().to_owned();
+        if let Err(e) = dir.close() {
+            log::message(
+                Level::Warning,
+                &format!(
+                    "failed to remove the temporary root directory `{}`: {}",
+                    path.display(),
+                    e
+                ),
+            );
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Artifact {
+    format: String,
+    path: PathBuf,
+}
+
+impl Artifact {
+    fn new<F: Into<String>>(format: F, path: PathBuf) -> Self {
+        Self {
+            format: format.into(),
+            path,
+        }
+    }
+
+    pub fn format(&self) -> &str {
+        &self.format
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 }
 
 pub struct Builder {
     bsd: BaseSystemDownloader,
+    client: http::Client,
+    cache: Option<Cache>,
     conf: Configuration,
 }
 
 impl Builder {
-    pub fn new(base_system_downloader: BaseSystemDownloader, configuration: Configuration) -> Self {
+    pub fn new(
+        base_system_downloader: BaseSystemDownloader,
+        client: http::Client,
+        cache: Option<Cache>,
+        configuration: Configuration,
+    ) -> Self {
         Self {
             bsd: base_system_downloader,
+            client,
+            cache,
             conf: configuration,
         }
     }
 
+    pub fn configuration(&self) -> &Configuration {
+        &self.conf
+    }
+
     pub fn create_chroot(&self) -> Result<BuildDir, ()> {
+        // Keep other runs off the persistent Nix store while this one uses it
+        let store_lock = self.lock_nix_store()?;
+
         // Create a temporary root directory
-        let build_dir = self.create_build_directory()?;
+        let mut build_dir = self.create_build_directory()?;
+        build_dir.store_lock = store_lock;
 
         // Copy Nix configuration into the temporary root directory
         self.copy_nix_configuration(build_dir.path())?;
 
+        // Copy the Nix flake into the temporary root directory
+        self.copy_flake(build_dir.path())?;
+
         // Download the minimal chroot system tarball (and verify its integrity)
         let tarball_path = self.download_rootfs_tarball(build_dir.path())?;
 
-        // Extract the rootfs tarball
+        // Look up a snapshot of an identically bootstrapped build root
+        let snapshot = self.lookup_snapshot(&tarball_path);
+        let restored = match &snapshot {
+            Some(s) if s.exists() => {
+                self.restore_snapshot(s, build_dir.path())?;
+                true
+            }
+            _ => {
+                // Extract the rootfs tarball
+                extract_rootfs_tarball(&tarball_path)?;
+                false
+            }
+        };
+
+        // Configure name resolution in the build environment
+        self.write_dns_configuration(build_dir.path())?;
+
+        if !restored {
+            // Trust the extra CA certificates in the build environment
+            self.install_ca_bundle(build_dir.path())?;
+
+            // Download the pinned nixpkgs tarball (and verify its integrity)
+            self.download_nixpkgs_tarball(build_dir.path())?;
+
+            build_dir.snapshot = snapshot;
+        }
+
+        Ok(build_dir)
+    }
+
+    /// Prepares a build root with only what running Nix on the persistent store takes
+    pub fn create_gc_chroot(&self) -> Result<BuildDir, ()> {
+        // Keep other runs off the persistent Nix store while this one uses it
+        let store_lock = self.lock_nix_store()?;
+
+        // Create a temporary root directory
+        let mut build_dir = self.create_build_directory()?;
+        build_dir.store_lock = store_lock;
+
+        // Download and extract the minimal chroot system tarball
+        let tarball_path = self.download_rootfs_tarball(build_dir.path())?;
         extract_rootfs_tarball(&tarball_path)?;
 
+        // Nix is installed from the Alpine repositories
+        self.write_dns_configuration(build_dir.path())?;
+        self.install_ca_bundle(build_dir.path())?;
+
         Ok(build_dir)
     }
 
+    fn lock_nix_store(&self) -> Result<Option<DirectoryLock>, ()> {
+        let path = match self.conf.nix_store() {
+            Some(s) => s.path(),
+            None => return Ok(None),
+        };
+
+        stage!("lock_nix_store", "Locking the persistent Nix store...");
+
+        let lock = match DirectoryLock::try_acquire(path) {
+            Ok(Some(l)) => l,
+            Ok(None) => {
+                log::info(&format!(
+                    "Waiting for another run to release `{}`...",
+                    path.display()
+                ));
+
+                match DirectoryLock::wait(path) {
+                    Ok(l) => l,
+                    Err(e) => err!("{}", e),
+                }
+            }
+            Err(e) => err!("{}", e),
+        };
+
+        ok!("locked `{}`", path.display());
+
+        Ok(Some(lock))
+    }
+
+    /// Returns the disk space used by the persistent Nix store
+    pub fn measure_nix_store(&self) -> Result<u64, ()> {
+        stage!("measure_nix_store", "Measuring the persistent Nix store...");
+
+        let path = match self.conf.nix_store() {
+            Some(s) => s.path(),
+            None => err!("the `nix_store` option is not set"),
+        };
+
+        match store::disk_usage(path) {
+            Ok(s) => {
+                ok!("`{}` uses {} bytes", path.display(), s);
+
+                Ok(s)
+            }
+            Err(e) => err!("failed to measure `{}`: {}", path.display(), e),
+        }
+    }
+
+    /// Returns the cache entry of the snapshot matching the inputs of this build
+    fn lookup_snapshot(&self, tarball_path: &Path) -> Option<PathBuf> {
+        if !self.conf.snapshot() {
+            return None;
+        }
+
+        stage!(
+            else None;
+            "lookup_snapshot",
+            "Looking up a snapshot of the bootstrapped build environment..."
+        );
+
+        let cache = match &self.cache {
+            Some(c) => c,
+            None => {
+                log::message(
+                    Level::Warning,
+                    "snapshots are disabled, the cache directory is unknown",
+                );
+
+                return None;
+            }
+        };
+
+        let key = match self.snapshot_key(tarball_path) {
+            Ok(k) => k,
+            Err(e) => {
+                log::message(Level::Warning, &format!("snapshots are disabled: {}", e));
+
+                return None;
+            }
+        };
+
+        match cache.lookup(SNAPSHOT_NAMESPACE, &key) {
+            Some(p) => {
+                ok!("found the `{}` snapshot", key);
+
+                // keeps it from being evicted while it's being restored
+                if let Err(e) = cache.touch(SNAPSHOT_NAMESPACE, &key) {
+                    log::message(
+                        Level::Warning,
+                        &format!("failed to mark the `{}` snapshot as used: {}", key, e),
+                    );
+                }
+
+                Some(p)
+            }
+            None => {
+                ok!("the `{}` snapshot will be created after bootstrapping", key);
+
+                Some(cache.entry_path(SNAPSHOT_NAMESPACE, &key))
+            }
+        }
+    }
+
+    fn snapshot_key(&self, tarball_path: &Path) -> Result<String, String> {
+        let alpine = match hash_file::<Sha512>(tarball_path) {
+            Ok((_, h)) => h,
+            Err(e) => return Err(format!("failed to hash the base system tarball: {}", e)),
+        };
+
+        let nixpkgs = self.conf.nixpkgs();
+        let revision = match nixpkgs.url() {
+            Some(u) => format!("{} {}", u, nixpkgs.sha256().unwrap()),
+            None => {
+                let mirrors = self.conf.mirrors().nixpkgs();
+                match nixos::channel_revision(&self.client, &mirrors, nixpkgs.channel()) {
+                    Ok(r) => format!("{} {}", nixpkgs.channel(), r),
+                    Err(e) => return Err(format!("{}", e)),
+                }
+            }
+        };
+
+        let ca_bundle = match self.conf.http().ca_bundle() {
+            Some(c) => match hash_file::<Sha256>(c) {
+                Ok((_, h)) => h,
+                Err(e) => return Err(format!("failed to hash `{}`: {}", c.display(), e)),
+            },
+            None => String::from("none"),
+        };
+
+        log::write(&format!(
+            "Snapshot inputs: Alpine `{}`, nixpkgs `{}`, CA bundle `{}`",
+            alpine, revision, ca_bundle
+        ));
+
+        let mut hasher = Sha256::new();
+        for input in [&alpine, &revision, &ca_bundle] {
+            hasher.update(input.as_bytes());
+            hasher.update(b"\n");
+        }
+
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    fn restore_snapshot(&self, snapshot: &Path, build_root: &Path) -> Result<(), ()> {
+        stage!(
+            "restore_snapshot",
+            "Restoring the bootstrapped build environment..."
+        );
+
+        let mut source = snapshot.as_os_str().to_owned();
+        source.push("/.");
+        if let Err(e) =
+            run_host_command_checked("cp", [OsStr::new("-a"), &source, build_root.as_os_str()])
+        {
+            err!(
+                "failed to restore the `{}` snapshot: {}",
+                snapshot.display(),
+                e
+            );
+        }
+
+        // the snapshot is shared by builds with different Nix settings
+        let nix_conf_path = build_root.join("etc/nix/nix.conf");
+        if let Err(e) = std::fs::write(&nix_conf_path, self.nix_conf()) {
+            err!("failed to update `{}`: {}", nix_conf_path.display(), e);
+        }
+
+        ok!("restored the `{}` snapshot", snapshot.display());
+
+        Ok(())
+    }
+
+    /// Copies the bootstrapped build root into the `snapshot` cache entry
+    pub fn store_snapshot(&self, build_root: &Path, snapshot: &Path) {
+        stage!(
+            else;
+            "store_snapshot",
+            "Storing a snapshot of the bootstrapped build environment..."
+        );
+
+        match copy_snapshot(build_root, snapshot) {
+            Ok(_) => ok!("stored the `{}` snapshot", snapshot.display()),
+            Err(e) => {
+                log::message(
+                    Level::Warning,
+                    &format!("failed to store the snapshot: {}", e),
+                );
+
+                return;
+            }
+        }
+
+        if let Some(cache) = &self.cache {
+            evict_snapshots(cache);
+        }
+    }
+
+    fn write_dns_configuration(&self, root_path: &Path) -> Result<(), ()> {
+        stage!(
+            "fix_dns",
+            "Configuring DNS resolution in the build environment..."
+        );
+
+        let dns = self.conf.dns();
+        let resolv_conf = match dns.mode() {
+            DnsMode::Default => String::from("nameserver 8.8.8.8\n"),
+            DnsMode::Host => match host_resolv_conf() {
+                Ok(c) => c,
+                Err(e) => err!("failed to read the host DNS configuration: {}", e),
+            },
+            DnsMode::Custom => {
+                let mut lines: Vec<String> = dns
+                    .nameservers()
+                    .iter()
+                    .map(|n| format!("nameserver {}", n))
+                    .collect();
+                if !dns.search().is_empty() {
+                    lines.push(format!("search {}", dns.search().join(" ")));
+                }
+
+                lines.join("\n") + "\n"
+            }
+        };
+
+        let mut hosts = vec![
+            String::from("127.0.0.1\tlocalhost"),
+            String::from("::1\tlocalhost"),
+        ];
+        for (name, address) in dns.hosts() {
+            hosts.push(format!("{}\t{}", address, name));
+        }
+
+        let files = [
+            ("etc/resolv.conf", resolv_conf),
+            ("etc/hosts", hosts.join("\n") + "\n"),
+        ];
+        for (path, content) in files {
+            let path = root_path.join(path);
+            // may be a symlink pointing outside of the build root
+            let _ = std::fs::remove_file(&path);
+            if let Err(e) = std::fs::write(&path, content) {
+                err!("unable to create `{}` file: {}", path.display(), e);
+            }
+        }
+
+        ok!("created the `/etc/resolv.conf` and `/etc/hosts` files");
+
+        Ok(())
+    }
+
+    fn install_ca_bundle(&self, root_path: &Path) -> Result<(), ()> {
+        let ca_bundle = match self.conf.http().ca_bundle() {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+
+        stage!(
+            "install_ca_bundle",
+            "Installing the extra CA certificates..."
+        );
+
+        let pem = match std::fs::read(ca_bundle) {
+            Ok(p) => p,
+            Err(e) => err!(
+                "failed to read the CA bundle `{}`: {}",
+                ca_bundle.display(),
+                e
+            ),
+        };
+
+        // `update-ca-certificates` regenerates the bundle from this directory
+        // when apk installs the `ca-certificates` package
+        let local_certs = root_path.join("usr/local/share/ca-certificates");
+        let local_cert = local_certs.join("nixos-conjurer.crt");
+        if let Err(e) =
+            std::fs::create_dir_all(&local_certs).and_then(|_| std::fs::write(&local_cert, &pem))
+        {
+            err!("failed to create `{}`: {}", local_cert.display(), e);
+        }
+
+        let bundle_path = root_path.join(CA_BUNDLE_PATH.trim_start_matches('/'));
+        let result = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&bundle_path)
+            .and_then(|mut f| f.write_all(b"\n").and_then(|_| f.write_all(&pem)));
+        if let Err(e) = result {
+            err!("failed to update `{}`: {}", bundle_path.display(), e);
+        }
+
+        ok!("added certificates from `{}`", ca_bundle.display());
+
+        Ok(())
+    }
+
+    fn download_nixpkgs_tarball(&self, root_path: &Path) -> Result<(), ()> {
+        let nixpkgs = self.conf.nixpkgs();
+        let url = match nixpkgs.url() {
+            Some(u) => u,
+            None => return Ok(()),
+        };
+
+        stage!(
+            "download_nixpkgs",
+            "Downloading the pinned nixpkgs tarball..."
+        );
+
+        let tarball_path = root_path.join(NIXPKGS_TARBALL.trim_start_matches('/'));
+        if let Err(e) =
+            nixos::download_nixpkgs(&self.client, url, &nixpkgs.sha256().unwrap(), &tarball_path)
+        {
+            err!("{}", e);
+        }
+
+        ok!("downloaded and verified `{}`", url);
+
+        Ok(())
+    }
+
     fn download_rootfs_tarball(&self, root_path: &Path) -> Result<PathBuf, ()> {
-        println!("Downloading base system tarball...");
-        let base_system_tarball = root_path.join("alpine-minirootfs.tgz");
+        let base_system_tarball = root_path.join(ROOTFS_TARBALL);
+
+        if let Some(p) = self.conf.alpine().tarball() {
+            return copy_rootfs_tarball(
+                p,
+                &self.conf.alpine().sha512().unwrap(),
+                &base_system_tarball,
+            );
+        }
+
+        stage!("download_base_system", "Downloading base system tarball...");
         match self.bsd.download(&base_system_tarball) {
-            Ok(_) => {
+            Ok(Origin::Cache) => ok!("reused and verified the cached tarball"),
+            Ok(Origin::Network) => ok!("downloaded and verified the tarball"),
+            Ok(Origin::NetworkUncached(e)) => {
+                log::message(Level::Warning, &format!("{}", e));
                 ok!("downloaded and verified the tarball");
-                Ok(base_system_tarball)
             }
             Err(e) => err!("{}", e),
         }
+
+        Ok(base_system_tarball)
     }
 
-    pub fn pull_image(&self, tarball_path: &Path) -> Result<(), ()> {
-        println!("Pulling the resulting image from the temporary root...");
+    pub fn pull_image(&self, artifact: &Artifact, build_root: &Path) -> Result<(), ()> {
+        stage!(
+            "pull_image",
+            "Pulling the resulting `{}` image from the temporary root...",
+            artifact.format()
+        );
 
-        let output_path = match self.conf.output_path() {
+        // the persistent Nix store and the scratch mounts only exist in the
+        // build namespace, in the order they are mounted in
+        let mut mounts = vec![];
+        if let Some(s) = self.conf.nix_store() {
+            mounts.push((Path::new("/nix"), s.path()));
+        }
+        for m in self.conf.sandbox().mounts() {
+            mounts.push((m.target(), m.source()));
+        }
+        let tarball_path = host_path(build_root, artifact.path(), &mounts);
+        let file_name = match tarball_path.file_name() {
             Some(p) => PathBuf::from(p),
-            None => match tarball_path.file_name() {
+            _ => err!(
+                "failed to get the image filename from: `{}`",
+                tarball_path.display()
+            ),
+        };
+
+        // With several output formats the output path is a directory that
+        // holds a separate sub-directory for every format, since different
+        // formats may produce identically named images (e.g. `nixos.img`)
+        let output_path = if self.conf.output_formats().len() > 1 {
+            let output_dir = match self.conf.output_path() {
+                Some(p) => p.join(artifact.format()),
+                None => PathBuf::from(artifact.format()),
+            };
+
+            if let Err(e) = std::fs::create_dir_all(&output_dir) {
+                err!(
+                    "failed to create the output directory `{}`: {}",
+                    output_dir.display(),
+                    e
+                );
+            }
+
+            output_dir.join(file_name)
+        } else {
+            match self.conf.output_path() {
                 Some(p) => PathBuf::from(p),
-                _ => err!(
-                    "failed to get the image filename from: `{}`",
-                    tarball_path.display()
-                ),
-            },
+                None => file_name,
+            }
         };
 
         if let Err(e) = std::fs::copy(&tarball_path, &output_path) {
@@ -122,47 +726,216 @@ impl Builder {
             );
         }
 
+        let (size, sha256) = match hash_file::<Sha256>(&output_path) {
+            Ok(h) => h,
+            Err(e) => err!(
+                "failed to compute the checksum of `{}`: {}",
+                output_path.display(),
+                e
+            ),
+        };
+
         ok!(
-            "successfully copied the `{}` image file",
-            output_path.display()
+            "successfully copied the `{}` image file ({} bytes, SHA-256 `{}`)",
+            output_path.display(),
+            size,
+            sha256
         );
+        log::event(Event::Artifact {
+            format: artifact.format(),
+            path: &output_path,
+            size,
+            sha256: &sha256,
+        });
 
         Ok({})
     }
 
-    pub fn run_build_process(&self) -> Result<PathBuf, ()> {
-        // Fix resolv.conf
-        fix_resolv_conf()?;
+    pub fn run_build_process(&self) -> Result<Vec<Artifact>, ()> {
+        // Prepare the environment required to run `nixos-generate`
+        self.prepare_build_environment()?;
+
+        // Generate an image in every requested format
+        let mut artifacts = vec![];
+        for format in self.conf.output_formats() {
+            let image_path = self.nixos_generate(&format)?;
+            artifacts.push(Artifact::new(format.to_string_lossy(), image_path));
+        }
+
+        Ok(artifacts)
+    }
+
+    pub fn run_bootstrap_process(&self) -> Result<(), ()> {
+        self.prepare_build_environment()
+    }
+
+    pub fn run_gc_process(&self, max_freed: Option<u64>) -> Result<(), ()> {
+        // Only Nix itself is needed to collect the garbage
+        if !Path::new(BOOTSTRAP_MARKER).exists() {
+            add_repositories(&self.conf.mirrors().alpine())?;
+            install_nix(&self.nix_conf())?;
+        }
+
+        stage!(
+            "collect_garbage",
+            "Removing unused paths from the persistent Nix store..."
+        );
+
+        if let Err(e) = nixos::collect_garbage(max_freed) {
+            err!("{}", e);
+        }
+
+        ok!("collected the garbage");
+
+        Ok(())
+    }
+
+    pub fn run_shell_process(&self) -> Result<(), ()> {
+        // Prepare the environment required to run `nixos-generate`
+        self.prepare_build_environment()?;
+
+        stage!(
+            "shell",
+            "Starting an interactive shell in the build environment..."
+        );
+
+        match run_interactive("/bin/sh", ["-l"]) {
+            Ok(_) => ok!("the interactive shell has exited"),
+            Err(e) => err!("{}", e),
+        }
+
+        Ok(())
+    }
+
+    pub fn run_formats_process(&self) -> Result<String, ()> {
+        // Prepare the environment required to run `nixos-generate`
+        self.prepare_build_environment()?;
+
+        stage!(
+            "list_formats",
+            "Listing the formats supported by `nixos-generate`..."
+        );
+
+        let result = match run_command_checked("nixos-generate", ["--list"]) {
+            Ok(o) => o,
+            Err(e) => err!("{}", e),
+        };
+
+        ok!("retrieved the list of supported formats");
+
+        Ok(String::from_utf8_lossy(&result.stdout).into_owned())
+    }
+
+    fn prepare_build_environment(&self) -> Result<(), ()> {
+        // A restored snapshot or an earlier run has done everything already
+        if Path::new(BOOTSTRAP_MARKER).exists() {
+            log::info("Reusing the bootstrapped build environment...");
+
+            return Ok(());
+        }
 
         // Add the Alpine edge repository
-        add_repositories()?;
+        add_repositories(&self.conf.mirrors().alpine())?;
 
         // Install bash, xz, tar, nix via apk
-        install_nix()?;
+        install_nix(&self.nix_conf())?;
 
         // Add the nixpkg channel and update channels
-        nix_update_channels()?;
+        self.nix_update_channels()?;
 
         // Install `nixos-generate` through nix
         install_nixos_generate()?;
 
-        // Generate an image
-        let image_path = self.nixos_generate()?;
+        if let Err(e) = File::create(BOOTSTRAP_MARKER) {
+            err!("failed to create `{}`: {}", BOOTSTRAP_MARKER, e);
+        }
 
-        Ok(image_path)
+        Ok(())
+    }
+
+    fn nix_conf(&self) -> String {
+        let mut nix_conf = vec![String::from("build-users-group =")];
+
+        if self.conf.flake().is_some() {
+            nix_conf.push(String::from("experimental-features = nix-command flakes"));
+        }
+
+        if self.conf.http().ca_bundle().is_some() {
+            nix_conf.push(format!("ssl-cert-file = {}", CA_BUNDLE_PATH));
+        }
+
+        // one single-threaded build job per available CPU
+        if let Some(c) = self.conf.limits().cpus() {
+            nix_conf.push(format!("max-jobs = {}", c));
+            nix_conf.push(String::from("cores = 1"));
+        }
+
+        nix_conf.join("\n")
     }
 
-    fn nixos_generate(&self) -> Result<PathBuf, ()> {
-        println!(
+    fn nix_update_channels(&self) -> Result<(), ()> {
+        stage!("update_channels", "Configure and update Nix channels...");
+
+        // the pinned tarball was downloaded into the root before entering the namespace
+        if self.conf.nixpkgs().url().is_some() {
+            let channel = format!("file://{}", NIXPKGS_TARBALL);
+            if let Err(e) = nixos::add_channel(&channel, NIXPKGS_CHANNEL_NAME) {
+                err!("{}", e)
+            }
+
+            if let Err(e) = nixos::update_channels() {
+                err!("{}", e)
+            }
+
+            ok!("pinned nixpkgs channel was added and channels were successfully updated");
+
+            return Ok(());
+        }
+
+        let mut errors = vec![];
+        for mirror in self.conf.mirrors().nixpkgs() {
+            let channel = format!("{}/{}", mirror, self.conf.nixpkgs().channel());
+            if let Err(e) = nixos::add_channel(&channel, NIXPKGS_CHANNEL_NAME) {
+                err!("{}", e)
+            }
+
+            match nixos::update_channels() {
+                Ok(_) => {
+                    ok!(
+                        "nixpkgs channel `{}` was added and channels were successfully updated",
+                        channel
+                    );
+
+                    return Ok(());
+                }
+                Err(e) => errors.push(format!("`{}`: {}", channel, e)),
+            }
+        }
+
+        err!(
+            "none of the nixpkgs mirrors is usable: {}",
+            errors.join("; ")
+        )
+    }
+
+    fn nixos_generate(&self, format: &OsStr) -> Result<PathBuf, ()> {
+        stage!(
+            "generate_image",
             "Generating a NixOS `{}` image...",
-            self.conf.output_format().to_string_lossy()
+            format.to_string_lossy()
         );
 
-        let mut args = vec![OsString::from("-f"), self.conf.output_format().to_owned()];
-        if self.conf.has_nix_configuration() {
+        let mut args = vec![OsString::from("-f"), format.to_owned()];
+        if let Some(f) = self.conf.flake() {
+            // `path:` avoids the need for git and copies untracked files as well
+            args.append(&mut vec![
+                OsString::from("--flake"),
+                OsString::from(format!("path:{}#{}", FLAKE_PATH, f.configuration())),
+            ]);
+        } else if self.conf.has_nix_configuration() {
             args.append(&mut vec![
                 OsString::from("-c"),
-                OsString::from("/configuration.nix"),
+                self.nix_configuration_file().into_os_string(),
             ]);
         }
 
@@ -185,12 +958,27 @@ impl Builder {
         Ok(image_path)
     }
 
+    /// Path of the Nix configuration entry point inside the build root
+    fn nix_configuration_file(&self) -> PathBuf {
+        match self.conf.nix_configuration_dir() {
+            Some(_) => Path::new(CONFIGURATION_DIR_PATH).join(self.conf.nix_configuration_entry()),
+            None => PathBuf::from("/configuration.nix"),
+        }
+    }
+
     fn copy_nix_configuration(&self, build_root: &Path) -> Result<(), ()> {
         if !self.conf.has_nix_configuration() {
             return Ok({});
         }
 
-        println!("Creating a Nix build configuration file...");
+        if let Some(p) = self.conf.nix_configuration_dir() {
+            return self.copy_nix_configuration_dir(p, build_root);
+        }
+
+        stage!(
+            "write_configuration",
+            "Creating a Nix build configuration file..."
+        );
 
         let config_path = build_root.join("configuration.nix");
 
@@ -226,12 +1014,72 @@ impl Builder {
         }
     }
 
+    fn copy_nix_configuration_dir(&self, source: &Path, build_root: &Path) -> Result<(), ()> {
+        stage!(
+            "copy_configuration_dir",
+            "Copying the Nix build configuration directory..."
+        );
+
+        let entry = source.join(self.conf.nix_configuration_entry());
+        if !entry.is_file() {
+            err!(
+                "Nix configuration entry point `{}` doesn't exist",
+                entry.display()
+            );
+        }
+
+        let config_dir = build_root.join(CONFIGURATION_DIR_PATH.trim_start_matches('/'));
+        if let Err(e) = copy_tree(source, &config_dir) {
+            err!(
+                "failed to copy Nix configuration directory from `{}` to `{}`: {}",
+                source.display(),
+                config_dir.display(),
+                e
+            );
+        }
+
+        ok!(
+            "copied Nix configuration directory from `{}` to `{}`",
+            source.display(),
+            config_dir.display()
+        );
+
+        Ok(())
+    }
+
+    fn copy_flake(&self, build_root: &Path) -> Result<(), ()> {
+        let flake = match self.conf.flake() {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+
+        stage!("copy_flake", "Copying the Nix flake...");
+
+        let flake_path = build_root.join(FLAKE_PATH.trim_start_matches('/'));
+        if let Err(e) = copy_tree(flake.path(), &flake_path) {
+            err!(
+                "failed to copy the Nix flake from `{}` to `{}`: {}",
+                flake.path().display(),
+                flake_path.display(),
+                e
+            );
+        }
+
+        ok!(
+            "copied the Nix flake from `{}` to `{}`",
+            flake.path().display(),
+            flake_path.display()
+        );
+
+        Ok(())
+    }
+
     fn create_build_directory(&self) -> Result<BuildDir, ()> {
-        println!("Creating a temporary root directory...");
+        stage!("create_build_dir", "Creating a temporary root directory...");
 
         let result = match self.conf.temporary_dir() {
-            Some(t) => TempDir::new_in(t, "nixosconj"),
-            None => TempDir::new("nixosconj"),
+            Some(t) => TempDir::new_in(t, BUILD_DIR_PREFIX),
+            None => TempDir::new(BUILD_DIR_PREFIX),
         };
 
         let temp_dir = match result {
@@ -239,25 +1087,180 @@ impl Builder {
             Err(e) => err!("{}", e),
         };
 
+        let lock = match DirectoryLock::try_acquire(temp_dir.path()) {
+            Ok(Some(l)) => l,
+            Ok(None) => err!("`{}` is locked by another run", temp_dir.path().display()),
+            Err(e) => err!("{}", e),
+        };
+
         ok!("successfully created `{}`", temp_dir.path().display());
 
-        Ok(BuildDir::new(temp_dir))
+        Ok(BuildDir::new(temp_dir, lock))
+    }
+
+    pub fn clean_build_directories(&self) -> Result<(), ()> {
+        stage!(
+            "clean_build_dirs",
+            "Removing leftover temporary root directories..."
+        );
+
+        let temporary_dir = match self.conf.temporary_dir() {
+            Some(t) => PathBuf::from(t),
+            None => std::env::temp_dir(),
+        };
+
+        let entries = match std::fs::read_dir(&temporary_dir) {
+            Ok(e) => e,
+            Err(e) => err!(
+                "failed to list the `{}` directory: {}",
+                temporary_dir.display(),
+                e
+            ),
+        };
+
+        let mut failed = false;
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if !is_build_dir_name(&entry.file_name().to_string_lossy()) || !path.is_dir() {
+                continue;
+            }
+
+            // running builds hold the lock until their directory is removed
+            let _lock = match DirectoryLock::try_acquire(&path) {
+                Ok(Some(l)) => l,
+                Ok(None) => {
+                    log::info(&format!(
+                        "... SKIP: `{}` is used by a running build",
+                        path.display()
+                    ));
+                    continue;
+                }
+                Err(e) => {
+                    log::message(Level::Error, &e);
+                    failed = true;
+                    continue;
+                }
+            };
+
+            fix_permissions(&path);
+            match std::fs::remove_dir_all(&path) {
+                Ok(_) => ok!("removed `{}`", path.display()),
+                Err(e) => {
+                    log::message(
+                        Level::Error,
+                        &format!("failed to remove `{}`: {}", path.display(), e),
+                    );
+                    failed = true;
+                }
+            }
+        }
+
+        if failed {
+            return Err(());
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `name` is `BUILD_DIR_PREFIX` followed by the suffix `TempDir` adds
+fn is_build_dir_name(name: &str) -> bool {
+    match name
+        .strip_prefix(BUILD_DIR_PREFIX)
+        .and_then(|n| n.strip_prefix('.'))
+    {
+        Some(suffix) => suffix.len() == 12 && suffix.chars().all(|c| c.is_ascii_alphanumeric()),
+        None => false,
     }
 }
 
+fn copy_rootfs_tarball(source: &Path, sha512: &str, destination: &Path) -> Result<PathBuf, ()> {
+    stage!(
+        "copy_base_system",
+        "Copying the local base system tarball..."
+    );
+
+    if let Err(e) = copy(source, destination) {
+        err!(
+            "failed to copy the base system tarball from `{}` to `{}`: {}",
+            source.display(),
+            destination.display(),
+            e
+        );
+    }
+
+    if let Err(e) = alpine::verify_checksum(destination, sha512) {
+        err!("`{}`: {}", source.display(), e);
+    }
+
+    ok!("copied and verified the `{}` tarball", source.display());
+
+    Ok(destination.to_owned())
+}
+
 fn extract_rootfs_tarball(tarball_path: &Path) -> Result<(), ()> {
-    println!("Extracting base system tarball...");
+    stage!("extract_base_system", "Extracting base system tarball...");
     match extract(&tarball_path) {
         Ok(_) => {
             ok!("`{}` was successfully extracted", tarball_path.display());
             Ok({})
         }
-        Err(e) => err!("{}", e),
+        Err(e) => err!("{}", e),
+    }
+}
+
+/// Creates a cgroup that enforces the `limits`, if any are set and cgroup v2 is delegated
+pub fn create_cgroup(limits: &LimitsConfiguration) -> Option<Cgroup> {
+    if !limits.has_process_limits() {
+        return None;
+    }
+
+    stage!(else None; "apply_limits", "Applying resource limits...");
+
+    match Cgroup::create(limits) {
+        Ok(c) => {
+            ok!("created the `{}` cgroup", c.path().display());
+
+            Some(c)
+        }
+        Err(e) => {
+            log::message(
+                Level::Warning,
+                &format!("falling back to `setrlimit`, cgroup v2 is unusable: {}", e),
+            );
+            if limits.pids().is_some() {
+                log::message(
+                    Level::Warning,
+                    "`limits.pids` is not enforced without a cgroup",
+                );
+            }
+
+            None
+        }
+    }
+}
+
+/// Subjects the calling process and all of its future children to the limits
+pub fn enter_limits(limits: &LimitsConfiguration, cgroup: Option<&Cgroup>) -> Result<(), ()> {
+    if let Some(c) = cgroup {
+        if let Err(e) = c.enter() {
+            err!("failed to enter the cgroup: {}", e);
+        }
+    }
+
+    if let Err(e) = limits::apply_rlimits(limits, cgroup.is_some()) {
+        err!("{}", e);
     }
+
+    Ok(())
 }
 
-pub fn setup_namespace(root_path: &Path) -> Result<(), ()> {
-    println!("Entering the private namespace...");
+pub fn setup_namespace(
+    root_path: &Path,
+    sandbox: &SandboxConfiguration,
+    nix_store: Option<&Path>,
+) -> Result<(), ()> {
+    stage!("enter_namespace", "Entering the private namespace...");
 
     let uid = getuid();
     let gid = getgid();
@@ -272,6 +1275,61 @@ pub fn setup_namespace(root_path: &Path) -> Result<(), ()> {
         err!("failed to enter the namespace: {}", e);
     }
 
+    // Files can only be created once the user is mapped.
+    //
+    // In the case of gid_map, use of the setgroups(2) system
+    // call must first be denied by writing "deny" to the
+    // /proc/[pid]/setgroups file before writing to gid_map.
+    // from: man user_namespaces(7)
+    let setgroups_file_path = "/proc/self/setgroups";
+    let mut setgroups_file = match File::create(setgroups_file_path) {
+        Ok(f) => f,
+        Err(e) => err!("unable to create the `{}` file: {}", setgroups_file_path, e),
+    };
+    if let Err(e) = setgroups_file.write_all(b"deny") {
+        err!(
+            "unable to write to the `{}` file: {}",
+            setgroups_file_path,
+            e
+        )
+    }
+
+    // create UID map
+    let uid_map_file_path = "/proc/self/uid_map";
+    let mut uid_map_file = match File::create(uid_map_file_path) {
+        Ok(f) => f,
+        Err(e) => err!(
+            "failed to create the UID map file `{}`: {}",
+            uid_map_file_path,
+            e
+        ),
+    };
+    if let Err(e) = uid_map_file.write_all(format!("0 {} 1", uid).as_bytes()) {
+        err!(
+            "failed to write new uid mapping to `{}`: {}",
+            uid_map_file_path,
+            e
+        );
+    }
+
+    // create GID map
+    let gid_map_file_path = "/proc/self/gid_map";
+    let mut gid_map_file = match File::create(gid_map_file_path) {
+        Ok(f) => f,
+        Err(e) => err!(
+            "failed to create the UID map file `{}`: {}",
+            gid_map_file_path,
+            e
+        ),
+    };
+    if let Err(e) = gid_map_file.write_all(format!("0 {} 1", gid).as_bytes()) {
+        err!(
+            "failed to write new uid mapping to `{}`: {}",
+            gid_map_file_path,
+            e
+        );
+    }
+
     // create a directory for new root
     let new_root = root_path.join("new_root");
     if let Err(e) = std::fs::create_dir_all(&new_root) {
@@ -297,23 +1355,51 @@ pub fn setup_namespace(root_path: &Path) -> Result<(), ()> {
         err!("failed to bind-mount the temporary root: {}", e);
     };
 
-    // mount /proc in the chroot
-    let proc_path = new_root.join("proc");
-    if let Err(e) = mount::bind("/proc", proc_path) {
-        err!("failed to mount `/proc` in the temporary root: {}", e);
-    };
+    if sandbox.hardened() {
+        populate_dev(&new_root.join("dev"), sandbox.kvm())?;
 
-    // mount /sys in the chroot
-    let sys_path = new_root.join("sys");
-    if let Err(e) = mount::bind("/sys", sys_path) {
-        err!("failed to mount `/sys` in the temporary root: {}", e);
-    };
+        // sysfs can't be mounted without a network namespace of its own
+        if let Err(e) = mount::bind_read_only("/sys", new_root.join("sys")) {
+            err!(
+                "failed to mount `/sys` read-only in the temporary root: {}",
+                e
+            );
+        }
 
-    // mount /dev in the chroot
-    let dev_path = new_root.join("dev");
-    if let Err(e) = mount::bind("/dev", dev_path) {
-        err!("failed to mount `/dev` in the temporary root: {}", e);
-    };
+        // /proc of the new PID namespace is mounted by its init process
+    } else {
+        // mount /proc in the chroot
+        let proc_path = new_root.join("proc");
+        if let Err(e) = mount::bind("/proc", proc_path) {
+            err!("failed to mount `/proc` in the temporary root: {}", e);
+        };
+
+        // mount /sys in the chroot
+        let sys_path = new_root.join("sys");
+        if let Err(e) = mount::bind("/sys", sys_path) {
+            err!("failed to mount `/sys` in the temporary root: {}", e);
+        };
+
+        // mount /dev in the chroot
+        let dev_path = new_root.join("dev");
+        if let Err(e) = mount::bind("/dev", dev_path) {
+            err!("failed to mount `/dev` in the temporary root: {}", e);
+        };
+    }
+
+    // the persistent Nix store replaces the one of the build root
+    if let Some(p) = nix_store {
+        let nix_path = chroot_path(&new_root, Path::new("/nix"), true)?;
+        if let Err(e) = mount::bind(p, &nix_path) {
+            err!(
+                "failed to mount the Nix store `{}` in the temporary root: {}",
+                p.display(),
+                e
+            );
+        }
+    }
+
+    mount_scratch(&new_root, sandbox)?;
 
     // change directory to the new root
     if let Err(e) = set_current_dir(&new_root) {
@@ -343,99 +1429,267 @@ pub fn setup_namespace(root_path: &Path) -> Result<(), ()> {
         err!("failed to change the current directory to `/`: {}", e);
     }
 
-    // In the case of gid_map, use of the setgroups(2) system
-    // call must first be denied by writing "deny" to the
-    // /proc/[pid]/setgroups file before writing to gid_map.
-    // from: man user_namespaces(7)
-    let setgroups_file_path = "/proc/self/setgroups";
-    let mut setgroups_file = match File::create(setgroups_file_path) {
-        Ok(f) => f,
-        Err(e) => err!("unable to create the `{}` file: {}", setgroups_file_path, e),
-    };
-    if let Err(e) = setgroups_file.write_all(b"deny") {
-        err!(
-            "unable to write to the `{}` file: {}",
-            setgroups_file_path,
-            e
-        )
+    ok!("configured and entered an isolate namespace");
+
+    Ok(())
+}
+
+/// Device nodes of the host passed through to the hardened build environment
+const DEVICES: [&str; 6] = ["null", "zero", "full", "random", "urandom", "tty"];
+
+/// Builds a `/dev` of bind-mounted host devices, as a user namespace can't create device nodes
+fn populate_dev(dev_path: &Path, kvm: bool) -> Result<(), ()> {
+    if let Err(e) = mount::mount(
+        Some("tmpfs"),
+        dev_path,
+        Some("tmpfs"),
+        Some(MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC),
+        Some("mode=755"),
+    ) {
+        err!("failed to mount a tmpfs on `{}`: {}", dev_path.display(), e);
     }
 
-    // create UID map
-    let uid_map_file_path = "/proc/self/uid_map";
-    let mut uid_map_file = match File::create(uid_map_file_path) {
-        Ok(f) => f,
-        Err(e) => err!(
-            "failed to create the UID map file `{}`: {}",
-            uid_map_file_path,
-            e
-        ),
-    };
-    if let Err(e) = uid_map_file.write_all(format!("0 {} 1", uid).as_bytes()) {
-        err!(
-            "failed to write new uid mapping to `{}`: {}",
-            uid_map_file_path,
-            e
-        );
+    let mut devices = DEVICES.to_vec();
+    if kvm {
+        if !Path::new("/dev/kvm").exists() {
+            err!("`/dev/kvm` is not available on the host");
+        }
+
+        devices.push("kvm");
     }
 
-    // create GID map
-    let gid_map_file_path = "/proc/self/gid_map";
-    let mut gid_map_file = match File::create(gid_map_file_path) {
-        Ok(f) => f,
-        Err(e) => err!(
-            "failed to create the UID map file `{}`: {}",
-            gid_map_file_path,
-            e
-        ),
+    for d in devices {
+        let device_path = dev_path.join(d);
+        if let Err(e) = File::create(&device_path) {
+            err!("failed to create `{}`: {}", device_path.display(), e);
+        }
+
+        if let Err(e) = mount::bind(Path::new("/dev").join(d), &device_path) {
+            err!("failed to mount `/dev/{}` in the temporary root: {}", d, e);
+        }
+    }
+
+    let pts_path = dev_path.join("pts");
+    let shm_path = dev_path.join("shm");
+    for p in [&pts_path, &shm_path] {
+        if let Err(e) = create_dir(p) {
+            err!("failed to create `{}`: {}", p.display(), e);
+        }
+    }
+
+    // a private instance, so the host terminals aren't reachable
+    if let Err(e) = mount::mount(
+        Some("devpts"),
+        &pts_path,
+        Some("devpts"),
+        Some(MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC),
+        Some("newinstance,ptmxmode=0666,mode=0620"),
+    ) {
+        err!("failed to mount devpts on `{}`: {}", pts_path.display(), e);
+    }
+
+    if let Err(e) = mount::mount(
+        Some("tmpfs"),
+        &shm_path,
+        Some("tmpfs"),
+        Some(MsFlags::MS_NOSUID | MsFlags::MS_NODEV),
+        Some("mode=1777"),
+    ) {
+        err!("failed to mount a tmpfs on `{}`: {}", shm_path.display(), e);
+    }
+
+    let links = [
+        ("ptmx", "pts/ptmx"),
+        ("fd", "/proc/self/fd"),
+        ("stdin", "/proc/self/fd/0"),
+        ("stdout", "/proc/self/fd/1"),
+        ("stderr", "/proc/self/fd/2"),
+    ];
+    for (name, target) in links {
+        if let Err(e) = symlink(target, dev_path.join(name)) {
+            err!("failed to create the `/dev/{}` link: {}", name, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Mounts the `/tmp` tmpfs and the bind mounts of the `sandbox` at `new_root`
+fn mount_scratch(new_root: &Path, sandbox: &SandboxConfiguration) -> Result<(), ()> {
+    if let Some(size) = sandbox.tmpfs_size() {
+        let tmp_path = chroot_path(new_root, Path::new("/tmp"), true)?;
+        if let Err(e) = mount::mount(
+            Some("tmpfs"),
+            &tmp_path,
+            Some("tmpfs"),
+            Some(MsFlags::MS_NOSUID | MsFlags::MS_NODEV),
+            Some(&format!("mode=1777,size={}", size)),
+        ) {
+            err!("failed to mount a tmpfs on `/tmp`: {}", e);
+        }
+    }
+
+    for m in sandbox.mounts() {
+        let is_dir = match m.source().metadata() {
+            Ok(m) => m.is_dir(),
+            Err(e) => err!(
+                "failed to access the mount source `{}`: {}",
+                m.source().display(),
+                e
+            ),
+        };
+
+        let target_path = chroot_path(new_root, m.target(), is_dir)?;
+        let result = match m.read_only() {
+            true => mount::bind_read_only(m.source(), &target_path),
+            false => mount::bind(m.source(), &target_path),
+        };
+        if let Err(e) = result {
+            err!(
+                "failed to mount `{}` at `{}` in the temporary root: {}",
+                m.source().display(),
+                m.target().display(),
+                e
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves the `path` of the build environment through its `(target, source)` bind mounts
+fn host_path(build_root: &Path, path: &Path, mounts: &[(&Path, &Path)]) -> PathBuf {
+    let path = Path::new("/").join(path);
+
+    // a later mount shadows the earlier ones
+    for (target, source) in mounts.iter().rev() {
+        match path.strip_prefix(target) {
+            Ok(p) if p.as_os_str().is_empty() => return source.to_path_buf(),
+            Ok(p) => return source.join(p),
+            Err(_) => {}
+        }
+    }
+
+    build_root.join(path.strip_prefix("/").unwrap_or(&path))
+}
+
+/// Resolves the `path` of the build environment to a mount point at `new_root`
+fn chroot_path(new_root: &Path, path: &Path, is_dir: bool) -> Result<PathBuf, ()> {
+    let relative = path.strip_prefix("/").unwrap_or(path);
+
+    let mut current = new_root.to_owned();
+    for c in relative.components() {
+        current.push(c);
+        match current.symlink_metadata() {
+            Ok(m) if m.file_type().is_symlink() => err!(
+                "`{}` is a symbolic link in the build environment, \
+                    the mount point must be a real path",
+                path.display()
+            ),
+            _ => {}
+        }
+    }
+
+    if current.exists() {
+        return Ok(current);
+    }
+
+    // the mount point and its missing parents are left out of the snapshot
+    let mut created = vec![];
+    let mut parent = current.as_path();
+    while !parent.exists() {
+        created.push(parent.strip_prefix(new_root).unwrap().to_owned());
+        parent = parent.parent().unwrap_or(new_root);
+    }
+    let mut list = vec![];
+    for c in created.iter().rev() {
+        list.extend_from_slice(c.as_os_str().as_bytes());
+        list.push(b'\n');
+    }
+    let list_path = new_root.join(MOUNT_POINTS_FILE);
+    if let Err(e) = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&list_path)
+        .and_then(|mut f| f.write_all(&list))
+    {
+        err!("failed to write `{}`: {}", list_path.display(), e);
+    }
+
+    let result = match is_dir {
+        true => std::fs::create_dir_all(&current),
+        false => std::fs::create_dir_all(current.parent().unwrap_or(new_root))
+            .and_then(|_| File::create(&current).map(|_| {})),
     };
-    if let Err(e) = gid_map_file.write_all(format!("0 {} 1", gid).as_bytes()) {
+    if let Err(e) = result {
         err!(
-            "failed to write new uid mapping to `{}`: {}",
-            gid_map_file_path,
+            "failed to create the mount point `{}`: {}",
+            path.display(),
             e
         );
     }
 
-    ok!("configured and entered an isolate namespace");
-
-    Ok({})
+    Ok(current)
 }
 
-fn fix_resolv_conf() -> Result<(), ()> {
-    println!("Fix DNS resolution in the namespace...");
-    let resolv_conf_path = "/etc/resolv.conf";
+/// Mounts a private `/proc` and detaches the host root from the new PID namespace
+pub fn setup_pid_namespace(sandbox: &SandboxConfiguration) -> Result<(), ()> {
+    if !sandbox.hardened() {
+        return Ok(());
+    }
 
-    if let Err(e) = std::fs::write(&resolv_conf_path, "nameserver 8.8.8.8") {
-        err!("Unable to create `{}` file: {}", resolv_conf_path, e);
+    if let Err(e) = mount::mount(
+        Some("proc"),
+        "/proc",
+        Some("proc"),
+        Some(MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC),
+        None as Option<&str>,
+    ) {
+        err!("failed to mount `/proc` of the build environment: {}", e);
     }
 
-    ok!("created a `{}` configuration file", resolv_conf_path);
+    if let Err(e) = umount2("/old_root", MntFlags::MNT_DETACH) {
+        err!("failed to detach the host root: {}", e);
+    }
 
     Ok({})
 }
 
-fn add_repositories() -> Result<(), ()> {
-    println!("Adding Alpine edge repositories...");
-    if let Err(e) = alpine::enable_edge_repositories() {
-        err!("{}", e);
-    }
+fn add_repositories(mirrors: &[String]) -> Result<(), ()> {
+    stage!("add_repositories", "Adding Alpine edge repositories...");
 
-    ok!("added main, community and testing edge repositories");
+    let mut errors = vec![];
+    for mirror in mirrors {
+        if let Err(e) = alpine::enable_edge_repositories(mirror) {
+            err!("{}", e);
+        }
 
-    Ok({})
-}
+        match alpine::update_repositories() {
+            Ok(_) => {
+                ok!(
+                    "added main, community and testing edge repositories from `{}`",
+                    mirror
+                );
 
-fn install_nix() -> Result<(), ()> {
-    println!("Installing the Nix package manager...");
-    if let Err(e) = alpine::update_repositories() {
-        err!("{}", e);
+                return Ok(());
+            }
+            Err(e) => errors.push(format!("`{}`: {}", mirror, e)),
+        }
     }
 
+    err!(
+        "none of the Alpine mirrors is usable: {}",
+        errors.join("; ")
+    )
+}
+
+fn install_nix(nix_conf: &str) -> Result<(), ()> {
+    stage!("install_nix", "Installing the Nix package manager...");
     if let Err(e) = alpine::install_packages(&["nix"]) {
         err!("{}", e);
     }
 
-    if let Err(e) = std::fs::write("/etc/nix/nix.conf", "build-users-group =") {
+    if let Err(e) = std::fs::write("/etc/nix/nix.conf", nix_conf) {
         err!("failed to create the `nix.conf` configuration file: {}", e);
     }
 
@@ -444,35 +1698,159 @@ fn install_nix() -> Result<(), ()> {
     Ok({})
 }
 
-fn nix_update_channels() -> Result<(), ()> {
-    println!("Configure and update Nix channels...");
+fn install_nixos_generate() -> Result<(), ()> {
+    stage!(
+        "install_nixos_generators",
+        "Installing the `nixpkgs.nixos-generators` package through Nix..."
+    );
 
-    if let Err(e) = nixos::add_channel("https://nixos.org/channels/nixpkgs-unstable") {
-        err!("{}", e)
+    if let Err(e) = nixos::install(vec!["nixpkgs.nixos-generators"]) {
+        err!("{}", e);
     }
 
-    if let Err(e) = nixos::update_channels() {
-        err!("{}", e)
+    ok!("successfully installed the package");
+
+    Ok({})
+}
+
+/// Returns the host `/etc/resolv.conf` with the upstream servers of systemd-resolved
+fn host_resolv_conf() -> std::io::Result<String> {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf")?;
+
+    let uses_stub = resolv_conf.lines().any(|l| {
+        l.split_whitespace()
+            .eq(["nameserver", RESOLVED_STUB_ADDRESS])
+    });
+    if uses_stub {
+        return std::fs::read_to_string(RESOLVED_UPSTREAM_RESOLV_CONF);
     }
 
-    ok!("nixpkgs channel was added and channels were successfully updated");
+    Ok(resolv_conf)
+}
 
-    Ok({})
+/// Recursively copies a directory, preserving symlinks and skipping `.git`
+fn copy_tree(source: &Path, destination: &Path) -> std::io::Result<()> {
+    let entries = WalkDir::new(source)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|e| e.file_name() != ".git");
+
+    for entry in entries {
+        let entry = entry?;
+        let target = destination.join(entry.path().strip_prefix(source).unwrap());
+        let file_type = entry.file_type();
+
+        if file_type.is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else if file_type.is_symlink() {
+            symlink(std::fs::read_link(entry.path())?, &target)?;
+        } else {
+            copy(entry.path(), &target)?;
+        }
+    }
+
+    Ok(())
 }
 
-fn install_nixos_generate() -> Result<(), ()> {
-    println!("Installing the `nixpkgs.nixos-generators` package through Nix...");
+/// Removes all snapshots but the `SNAPSHOTS_KEPT` most recently used ones
+fn evict_snapshots(cache: &Cache) {
+    let stale = match cache.least_recently_used(SNAPSHOT_NAMESPACE, SNAPSHOTS_KEPT) {
+        Ok(s) => s,
+        Err(e) => {
+            log::message(
+                Level::Warning,
+                &format!("failed to list the stored snapshots: {}", e),
+            );
 
-    if let Err(e) = nixos::install(vec!["nixpkgs.nixos-generators"]) {
-        err!("{}", e);
+            return;
+        }
+    };
+
+    for s in stale {
+        // the copied Nix store is read-only
+        fix_permissions(&s);
+        match std::fs::remove_dir_all(&s) {
+            Ok(_) => ok!("evicted the `{}` snapshot", s.display()),
+            Err(e) => log::message(
+                Level::Warning,
+                &format!("failed to evict the `{}` snapshot: {}", s.display(), e),
+            ),
+        }
     }
+}
 
-    ok!("successfully installed the package");
+/// Copies the build root into the `snapshot` directory through a temporary name
+fn copy_snapshot(build_root: &Path, snapshot: &Path) -> Result<(), String> {
+    let excluded = [
+        ROOTFS_TARBALL,
+        "configuration.nix",
+        CONFIGURATION_DIR_PATH.trim_start_matches('/'),
+        FLAKE_PATH.trim_start_matches('/'),
+        "new_root",
+        "old_root",
+        LOCK_FILE,
+        MOUNT_POINTS_FILE,
+    ];
+
+    let parent = snapshot.parent().unwrap();
+    let partial = parent.join(format!(
+        ".{}.{}.partial",
+        snapshot.file_name().unwrap().to_string_lossy(),
+        std::process::id()
+    ));
+    if let Err(e) = std::fs::create_dir_all(&partial) {
+        return Err(format!("failed to create `{}`: {}", partial.display(), e));
+    }
 
-    Ok({})
+    let entries = match std::fs::read_dir(build_root) {
+        Ok(e) => e,
+        Err(e) => return Err(format!("failed to list `{}`: {}", build_root.display(), e)),
+    };
+    let mut args = vec![OsString::from("-a")];
+    for entry in entries.filter_map(|e| e.ok()) {
+        if !excluded.iter().any(|x| entry.file_name() == *x) {
+            args.push(entry.path().into_os_string());
+        }
+    }
+    args.push(partial.clone().into_os_string());
+
+    let result = match run_host_command_checked("cp", &args) {
+        Ok(_) => {
+            remove_mount_points(build_root, &partial);
+
+            std::fs::rename(&partial, snapshot).map_err(|e| format!("{}", e))
+        }
+        Err(e) => Err(format!("{}", e)),
+    };
+
+    if result.is_err() {
+        fix_permissions(&partial);
+        let _ = std::fs::remove_dir_all(&partial);
+    }
+
+    match result {
+        // a concurrent run has stored the same snapshot first
+        Err(_) if snapshot.exists() => Ok(()),
+        r => r,
+    }
+}
+
+/// Removes the still empty mount points listed in the `build_root` from its `copy`
+fn remove_mount_points(build_root: &Path, copy: &Path) {
+    let list = std::fs::read(build_root.join(MOUNT_POINTS_FILE)).unwrap_or_default();
+
+    // children first, as they are listed after their parents
+    for line in list.split(|b| *b == b'\n').rev().filter(|l| !l.is_empty()) {
+        let path = copy.join(OsStr::from_bytes(line));
+        let _ = match path.symlink_metadata() {
+            Ok(m) if m.is_dir() => std::fs::remove_dir(&path),
+            Ok(m) if m.len() == 0 => std::fs::remove_file(&path),
+            _ => continue,
+        };
+    }
 }
 
-fn fix_permissions<P: AsRef<Path>>(path: P) {
+pub fn fix_permissions<P: AsRef<Path>>(path: P) {
     WalkDir::new(path.as_ref())
         .min_depth(1)
         .same_file_system(true)
@@ -484,3 +1862,60 @@ fn fix_permissions<P: AsRef<Path>>(path: P) {
             let _ = set_permissions(entry.path(), Permissions::from_mode(0o755));
         });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_path_resolves_the_nix_store() {
+        let root = Path::new("/tmp/build");
+        let store = [(Path::new("/nix"), Path::new("/var/lib/nix"))];
+        let image = Path::new("nix/store/abc-nixos.img/nixos.img");
+
+        assert_eq!(
+            host_path(root, image, &[]),
+            Path::new("/tmp/build/nix/store/abc-nixos.img/nixos.img")
+        );
+        assert_eq!(
+            host_path(root, image, &store),
+            Path::new("/var/lib/nix/store/abc-nixos.img/nixos.img")
+        );
+        assert_eq!(
+            host_path(root, Path::new("/nix/store/x"), &store),
+            Path::new("/var/lib/nix/store/x")
+        );
+        assert_eq!(
+            host_path(root, Path::new("nixos/x"), &store),
+            Path::new("/tmp/build/nixos/x")
+        );
+    }
+
+    #[test]
+    fn host_path_resolves_the_last_covering_mount() {
+        let root = Path::new("/tmp/build");
+        let mounts = [
+            (Path::new("/nix"), Path::new("/var/lib/nix")),
+            (Path::new("/nix"), Path::new("/scratch/nix")),
+            (Path::new("/nix/store/abc"), Path::new("/scratch/abc")),
+            (Path::new("/run/image"), Path::new("/srv/image.img")),
+        ];
+
+        assert_eq!(
+            host_path(root, Path::new("nix/store/def/nixos.img"), &mounts),
+            Path::new("/scratch/nix/store/def/nixos.img")
+        );
+        assert_eq!(
+            host_path(root, Path::new("nix/store/abc/nixos.img"), &mounts),
+            Path::new("/scratch/abc/nixos.img")
+        );
+        assert_eq!(
+            host_path(root, Path::new("run/image"), &mounts),
+            Path::new("/srv/image.img")
+        );
+        assert_eq!(
+            host_path(root, Path::new("run/images"), &mounts),
+            Path::new("/tmp/build/run/images")
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    fs::{copy, create_dir_all, read_dir, remove_file, rename, File},
+    io::Result as IoResult,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+pub struct Cache {
+    root: PathBuf,
+}
+
+impl Cache {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Returns `$XDG_CACHE_HOME/nixos-conjurer` or `$HOME/.cache/nixos-conjurer`
+    pub fn default_root() -> Option<PathBuf> {
+        if let Some(p) = std::env::var_os("XDG_CACHE_HOME") {
+            let p = PathBuf::from(p);
+            if p.is_absolute() {
+                return Some(p.join("nixos-conjurer"));
+            }
+        }
+
+        std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache").join("nixos-conjurer"))
+    }
+
+    pub fn entry_path(&self, namespace: &str, key: &str) -> PathBuf {
+        self.root.join(namespace).join(key)
+    }
+
+    pub fn lookup(&self, namespace: &str, key: &str) -> Option<PathBuf> {
+        let path = self.entry_path(namespace, key);
+        match path.exists() {
+            true => Some(path),
+            false => None,
+        }
+    }
+
+    /// Marks the entry as just used for `least_recently_used`
+    pub fn touch(&self, namespace: &str, key: &str) -> IoResult<()> {
+        File::open(self.entry_path(namespace, key))?.set_modified(SystemTime::now())
+    }
+
+    /// Returns the complete entries of `namespace` but the `kept` most recently used
+    pub fn least_recently_used(&self, namespace: &str, kept: usize) -> IoResult<Vec<PathBuf>> {
+        let mut entries = vec![];
+        for entry in read_dir(self.root.join(namespace))? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            entries.push((entry.metadata()?.modified()?, entry.path()));
+        }
+
+        entries.sort_by_key(|e| std::cmp::Reverse(e.0));
+
+        Ok(entries.into_iter().skip(kept).map(|(_, p)| p).collect())
+    }
+
+    pub fn evict(&self, namespace: &str, key: &str) -> IoResult<()> {
+        remove_file(self.entry_path(namespace, key))
+    }
+
+    /// Copies `source` into the cache through a temporary name
+    pub fn store<P: AsRef<Path>>(
+        &self,
+        namespace: &str,
+        key: &str,
+        source: P,
+    ) -> IoResult<PathBuf> {
+        let path = self.entry_path(namespace, key);
+        create_dir_all(self.root.join(namespace))?;
+
+        let partial_path = self.entry_path(
+            namespace,
+            &format!(".{}.{}.partial", key, std::process::id()),
+        );
+        if let Err(e) = copy(source, &partial_path) {
+            let _ = remove_file(&partial_path);
+
+            return Err(e);
+        }
+
+        rename(&partial_path, &path)?;
+
+        Ok(path)
+    }
+}
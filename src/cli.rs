@@ -0,0 +1,381 @@
+use crate::log;
+use std::path::PathBuf;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Command {
+    Build,
+    Validate,
+    Shell,
+    Clean,
+    Formats,
+    Doctor,
+    Gc,
+    Help,
+}
+
+impl Command {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "build" => Some(Command::Build),
+            "validate" => Some(Command::Validate),
+            "shell" => Some(Command::Shell),
+            "clean" => Some(Command::Clean),
+            "formats" => Some(Command::Formats),
+            "doctor" => Some(Command::Doctor),
+            "gc" => Some(Command::Gc),
+            "help" => Some(Command::Help),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Build => "build",
+            Command::Validate => "validate",
+            Command::Shell => "shell",
+            Command::Clean => "clean",
+            Command::Formats => "formats",
+            Command::Doctor => "doctor",
+            Command::Gc => "gc",
+            Command::Help => "help",
+        }
+    }
+
+    pub fn requires_configuration(&self) -> bool {
+        matches!(
+            self,
+            Command::Build | Command::Validate | Command::Shell | Command::Gc
+        )
+    }
+
+    /// Whether the configuration must list the images to build
+    pub fn requires_output_formats(&self) -> bool {
+        matches!(self, Command::Build | Command::Validate)
+    }
+
+    pub fn accepts_configuration(&self) -> bool {
+        !matches!(self, Command::Help)
+    }
+}
+
+pub struct Arguments {
+    command: Command,
+    configuration_path: Option<PathBuf>,
+    verbose: bool,
+    quiet: bool,
+    output_path: Option<PathBuf>,
+    temporary_dir: Option<PathBuf>,
+    output_formats: Vec<String>,
+    log_format: Option<String>,
+}
+
+impl Arguments {
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    pub fn configuration_path(&self) -> &Option<PathBuf> {
+        &self.configuration_path
+    }
+
+    pub fn verbose(&self) -> bool {
+        self.verbose
+    }
+
+    pub fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    pub fn output_path(&self) -> &Option<PathBuf> {
+        &self.output_path
+    }
+
+    pub fn temporary_dir(&self) -> &Option<PathBuf> {
+        &self.temporary_dir
+    }
+
+    pub fn output_formats(&self) -> &Vec<String> {
+        &self.output_formats
+    }
+
+    pub fn log_format(&self) -> &Option<String> {
+        &self.log_format
+    }
+}
+
+#[derive(Debug)]
+pub struct Error {
+    message: String,
+}
+
+impl Error {
+    fn new<M: AsRef<str>>(message: M) -> Self {
+        let message = message.as_ref().to_owned();
+
+        Self { message }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub fn parse(args: &[String]) -> Result<Arguments, Error> {
+    let mut command = None;
+    let mut positional = vec![];
+    let mut arguments = Arguments {
+        command: Command::Help,
+        configuration_path: None,
+        verbose: false,
+        quiet: false,
+        output_path: None,
+        temporary_dir: None,
+        output_formats: vec![],
+        log_format: None,
+    };
+
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((n, v)) if n.starts_with("--") => (n, Some(v.to_owned())),
+            _ => (arg.as_str(), None),
+        };
+
+        match name {
+            "-h" | "--help" => {
+                no_value(name, &inline_value)?;
+                command = Some(Command::Help)
+            }
+            "-v" | "--verbose" => {
+                no_value(name, &inline_value)?;
+                arguments.verbose = true
+            }
+            "-q" | "--quiet" => {
+                no_value(name, &inline_value)?;
+                arguments.quiet = true
+            }
+            "-o" | "--output" => {
+                arguments.output_path =
+                    Some(PathBuf::from(option_value(name, inline_value, &mut args)?))
+            }
+            "-t" | "--temporary-dir" => {
+                arguments.temporary_dir =
+                    Some(PathBuf::from(option_value(name, inline_value, &mut args)?))
+            }
+            "-f" | "--format" => {
+                let formats = option_value(name, inline_value, &mut args)?;
+                arguments.output_formats.extend(
+                    formats
+                        .split(',')
+                        .filter(|f| !f.is_empty())
+                        .map(|f| f.to_owned()),
+                );
+            }
+            "--log-format" => {
+                let format = option_value(name, inline_value, &mut args)?;
+                if log::Format::from_name(&format).is_none() {
+                    return Err(Error::new(format!("unknown log format `{}`", format)));
+                }
+
+                arguments.log_format = Some(format);
+            }
+            _ if name.starts_with('-') && name.len() > 1 => {
+                return Err(Error::new(format!("unknown option `{}`", name)))
+            }
+            _ if command.is_none() => match Command::from_name(name) {
+                Some(c) => command = Some(c),
+                None => return Err(Error::new(format!("unknown command `{}`", name))),
+            },
+            _ => positional.push(arg),
+        }
+    }
+
+    arguments.command = match command {
+        Some(c) => c,
+        None => return Err(Error::new("no command was specified")),
+    };
+
+    if arguments.command == Command::Help {
+        return Ok(arguments);
+    }
+
+    if arguments.verbose && arguments.quiet {
+        return Err(Error::new(
+            "`--verbose` and `--quiet` options are mutually exclusive",
+        ));
+    }
+
+    if positional.len() > 1 {
+        return Err(Error::new("too many arguments"));
+    }
+
+    if let Some(p) = positional.pop() {
+        if !arguments.command.accepts_configuration() {
+            return Err(Error::new("too many arguments"));
+        }
+
+        arguments.configuration_path = Some(PathBuf::from(p));
+    }
+
+    if arguments.command.requires_configuration() && arguments.configuration_path.is_none() {
+        return Err(Error::new("a configuration file path is required"));
+    }
+
+    Ok(arguments)
+}
+
+fn option_value<'a, I: Iterator<Item = &'a String>>(
+    name: &str,
+    inline_value: Option<String>,
+    args: &mut I,
+) -> Result<String, Error> {
+    if let Some(v) = inline_value {
+        return Ok(v);
+    }
+
+    match args.next() {
+        Some(v) => Ok(v.to_owned()),
+        None => Err(Error::new(format!("option `{}` requires a value", name))),
+    }
+}
+
+fn no_value(name: &str, inline_value: &Option<String>) -> Result<(), Error> {
+    match inline_value {
+        Some(_) => Err(Error::new(format!(
+            "option `{}` doesn't take a value",
+            name
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub fn usage(bin_name: &str) -> String {
+    format!(
+        "Usage: {bin} [options] <command> [<configuration-path>]
+
+Commands:
+  build <configuration-path>     Build the NixOS image described by the configuration
+  validate <configuration-path>  Check the configuration file and exit
+  shell <configuration-path>     Open an interactive shell in a prepared build environment
+  clean [<configuration-path>]   Remove leftover temporary build directories
+  formats [<configuration-path>] List the image formats supported by `nixos-generate`
+  doctor [<configuration-path>]  Check that the host is able to run builds
+  gc <configuration-path>        Shrink the persistent Nix store to `nix_store.max_size`
+  help                           Print this message
+
+Options:
+  -v, --verbose                  Print executed commands and all of their output
+  -q, --quiet                    Don't print the output of executed commands
+  -o, --output <path>            Override the `output_path` configuration option
+  -t, --temporary-dir <path>     Override the `temporary_dir` configuration option
+  -f, --format <format>[,...]    Override the `output_formats` configuration option
+      --log-format <text|json>   Print progress as text or as a JSON event stream
+  -h, --help                     Print this message",
+        bin = bin_name
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Arguments, Error> {
+        let args: Vec<String> = ["nixos-conjurer"]
+            .iter()
+            .chain(args)
+            .map(|a| a.to_string())
+            .collect();
+
+        parse(&args)
+    }
+
+    #[test]
+    fn parses_command_and_configuration_path() {
+        let arguments = parse_args(&["build", "conf.yml"]).unwrap();
+
+        assert_eq!(arguments.command(), Command::Build);
+        assert_eq!(
+            arguments.configuration_path(),
+            &Some(PathBuf::from("conf.yml"))
+        );
+    }
+
+    #[test]
+    fn parses_separate_and_inline_option_values() {
+        let arguments = parse_args(&[
+            "-o",
+            "out.img",
+            "--temporary-dir=/var/tmp",
+            "--format=iso,,qcow",
+            "-f",
+            "raw",
+            "--log-format",
+            "json",
+            "build",
+            "conf.yml",
+        ])
+        .unwrap();
+
+        assert_eq!(arguments.output_path(), &Some(PathBuf::from("out.img")));
+        assert_eq!(arguments.temporary_dir(), &Some(PathBuf::from("/var/tmp")));
+        assert_eq!(arguments.output_formats(), &vec!["iso", "qcow", "raw"]);
+        assert_eq!(arguments.log_format(), &Some("json".to_owned()));
+    }
+
+    #[test]
+    fn rejects_inline_values_of_flags() {
+        for flag in ["--verbose=1", "--quiet=", "--help=yes"] {
+            let e = match parse_args(&[flag, "build", "conf.yml"]) {
+                Ok(_) => panic!("`{}` was accepted", flag),
+                Err(e) => e,
+            };
+
+            assert!(e.to_string().contains("doesn't take a value"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        let cases: [&[&str]; 8] = [
+            &[],
+            &["frobnicate"],
+            &["--frobnicate", "build", "conf.yml"],
+            &["build"],
+            &["build", "conf.yml", "other.yml"],
+            &["-v", "-q", "build", "conf.yml"],
+            &["--log-format", "xml", "build", "conf.yml"],
+            &["build", "conf.yml", "-o"],
+        ];
+
+        for args in cases {
+            assert!(parse_args(args).is_err(), "{:?} was accepted", args);
+        }
+    }
+
+    #[test]
+    fn help_ignores_other_arguments() {
+        let arguments = parse_args(&["-v", "-q", "help", "extra", "args"]).unwrap();
+
+        assert_eq!(arguments.command(), Command::Help);
+    }
+
+    #[test]
+    fn configuration_is_optional_for_some_commands() {
+        let arguments = parse_args(&["clean"]).unwrap();
+
+        assert_eq!(arguments.command(), Command::Clean);
+        assert_eq!(arguments.configuration_path(), &None);
+    }
+
+    #[test]
+    fn only_image_builds_require_output_formats() {
+        assert!(Command::Build.requires_output_formats());
+        assert!(Command::Validate.requires_output_formats());
+        assert!(!Command::Shell.requires_output_formats());
+        assert!(!Command::Gc.requires_output_formats());
+    }
+}
@@ -1,18 +1,754 @@
+use crate::{
+    builder::{STAGES, TIMED_STAGES},
+    log,
+};
 use std::{
+    collections::{BTreeMap, HashMap},
     ffi::OsString,
     fs::File,
-    path::{Path, PathBuf},
+    net::IpAddr,
+    path::{Component, Path, PathBuf},
+    time::Duration,
 };
 
 use serde::Deserialize;
 
-#[derive(Deserialize)]
+/// Paths of the bootstrapped build environment a snapshot must include
+const SNAPSHOT_STATE_PATHS: [&str; 2] = ["/nix", "/root"];
+
+#[derive(Deserialize, Default)]
 pub struct Configuration {
     temporary_dir: Option<PathBuf>,
+    cache_dir: Option<PathBuf>,
+    #[serde(default)]
+    snapshot: bool,
+    log_dir: Option<PathBuf>,
     output_path: Option<PathBuf>,
-    output_format: String,
+    output_format: Option<String>,
+    output_formats: Option<Vec<String>>,
+    #[serde(default)]
+    verbose: bool,
+    #[serde(default)]
+    quiet: bool,
+    log_format: Option<String>,
     nix_configuration_path: Option<PathBuf>,
     nix_configuration: Option<String>,
+    nix_configuration_dir: Option<PathBuf>,
+    nix_configuration_entry: Option<PathBuf>,
+    #[serde(default)]
+    alpine: AlpineConfiguration,
+    #[serde(default)]
+    mirrors: MirrorsConfiguration,
+    #[serde(default)]
+    nixpkgs: NixpkgsConfiguration,
+    flake: Option<FlakeConfiguration>,
+    nix_store: Option<NixStoreConfiguration>,
+    #[serde(default)]
+    http: HttpConfiguration,
+    #[serde(default)]
+    timeouts: TimeoutsConfiguration,
+    #[serde(default)]
+    limits: LimitsConfiguration,
+    #[serde(default)]
+    dns: DnsConfiguration,
+    #[serde(default)]
+    sandbox: SandboxConfiguration,
+}
+
+#[derive(Deserialize, Default)]
+pub struct HttpConfiguration {
+    retries: Option<u32>,
+    retry_backoff: Option<u64>,
+    connect_timeout: Option<u64>,
+    request_timeout: Option<u64>,
+    http_proxy: Option<String>,
+    https_proxy: Option<String>,
+    no_proxy: Option<String>,
+    ca_bundle: Option<PathBuf>,
+}
+
+impl HttpConfiguration {
+    /// Number of retries per mirror, defaults to 3
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(3)
+    }
+
+    /// Initial delay between retries, defaults to 1 second
+    pub fn retry_backoff(&self) -> Duration {
+        Duration::from_secs(self.retry_backoff.unwrap_or(1))
+    }
+
+    /// Connection timeout, defaults to 30 seconds
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.connect_timeout.unwrap_or(30)))
+    }
+
+    /// Timeout of a whole request, including the body transfer; unlimited by default
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout.map(Duration::from_secs)
+    }
+
+    pub fn https_proxy(&self) -> &Option<String> {
+        &self.https_proxy
+    }
+
+    pub fn no_proxy(&self) -> &Option<String> {
+        &self.no_proxy
+    }
+
+    /// PEM bundle with extra CA certificates, e.g. of a TLS-intercepting proxy
+    pub fn ca_bundle(&self) -> &Option<PathBuf> {
+        &self.ca_bundle
+    }
+
+    /// Proxy variables to pass to the commands run in the build environment
+    pub fn proxy_environment(&self) -> Vec<(String, String)> {
+        let mut env = vec![];
+        let vars = [
+            ("http_proxy", &self.http_proxy),
+            ("https_proxy", &self.https_proxy),
+            ("no_proxy", &self.no_proxy),
+        ];
+
+        for (name, value) in vars {
+            if let Some(v) = value {
+                env.push((name.to_owned(), v.to_owned()));
+                env.push((name.to_uppercase(), v.to_owned()));
+            }
+        }
+
+        env
+    }
+}
+
+/// Limits in seconds for the whole run and for its individual stages
+#[derive(Deserialize, Default)]
+pub struct TimeoutsConfiguration {
+    overall: Option<u64>,
+    #[serde(default)]
+    stages: HashMap<String, u64>,
+}
+
+impl TimeoutsConfiguration {
+    fn validate(&self) -> Result<(), Error> {
+        if self.overall == Some(0) {
+            return Err(Error {
+                message: "`timeouts.overall` option must be positive".into(),
+            });
+        }
+
+        for (stage, timeout) in &self.stages {
+            if !STAGES.contains(&stage.as_str()) {
+                return Err(Error {
+                    message: format!(
+                        "`timeouts.stages` option contains unknown stage `{}`, \
+                            expected one of: {}",
+                        stage,
+                        STAGES.join(", ")
+                    ),
+                });
+            }
+
+            if !TIMED_STAGES.contains(&stage.as_str()) {
+                return Err(Error {
+                    message: format!(
+                        "`timeouts.stages` option can't limit the `{}` stage, \
+                            expected one of: {}",
+                        stage,
+                        TIMED_STAGES.join(", ")
+                    ),
+                });
+            }
+
+            if *timeout == 0 {
+                return Err(Error {
+                    message: format!("timeout of the `{}` stage must be positive", stage),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Limit of the whole run; unlimited by default
+    pub fn overall(&self) -> Option<Duration> {
+        self.overall.map(Duration::from_secs)
+    }
+
+    /// Limits of the individual stages, by stage name; unlimited by default
+    pub fn stages(&self) -> HashMap<String, Duration> {
+        self.stages
+            .iter()
+            .map(|(s, t)| (s.to_owned(), Duration::from_secs(*t)))
+            .collect()
+    }
+}
+
+/// Amount of bytes, a plain number or one with a `K`, `M`, `G` or `T` suffix
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+impl Size {
+    fn bytes(&self) -> Option<u64> {
+        let text = match self {
+            Self::Bytes(b) => return Some(*b),
+            Self::Text(t) => t.trim(),
+        };
+
+        let (number, multiplier) = match text.chars().last()?.to_ascii_uppercase() {
+            'K' => (&text[..text.len() - 1], 1 << 10),
+            'M' => (&text[..text.len() - 1], 1 << 20),
+            'G' => (&text[..text.len() - 1], 1 << 30),
+            'T' => (&text[..text.len() - 1], 1 << 40),
+            _ => (text, 1),
+        };
+
+        number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+    }
+}
+
+/// Resources available to the build environment
+#[derive(Deserialize, Default)]
+pub struct LimitsConfiguration {
+    memory: Option<Size>,
+    cpus: Option<u32>,
+    pids: Option<u64>,
+    disk: Option<Size>,
+}
+
+impl LimitsConfiguration {
+    fn validate(&self) -> Result<(), Error> {
+        let sizes = [("memory", &self.memory), ("disk", &self.disk)];
+        for (name, size) in sizes {
+            if let Some(s) = size {
+                if s.bytes().is_none_or(|b| b == 0) {
+                    return Err(Error {
+                        message: format!(
+                            "`limits.{}` option must be a positive size, e.g. `4G`",
+                            name
+                        ),
+                    });
+                }
+            }
+        }
+
+        if self.cpus == Some(0) || self.pids == Some(0) {
+            return Err(Error {
+                message: "`limits.cpus` and `limits.pids` options must be positive".into(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Memory limit in bytes
+    pub fn memory(&self) -> Option<u64> {
+        self.memory.as_ref().and_then(|s| s.bytes())
+    }
+
+    /// Number of CPUs the build may use, also passed to Nix as `max-jobs`
+    pub fn cpus(&self) -> Option<u32> {
+        self.cpus
+    }
+
+    /// Maximum number of processes
+    pub fn pids(&self) -> Option<u64> {
+        self.pids
+    }
+
+    /// Limit in bytes of the disk space used by the build root
+    pub fn disk(&self) -> Option<u64> {
+        self.disk.as_ref().and_then(|s| s.bytes())
+    }
+
+    /// Whether any limit enforced by a cgroup or `setrlimit` is set
+    pub fn has_process_limits(&self) -> bool {
+        self.memory.is_some() || self.cpus.is_some() || self.pids.is_some()
+    }
+}
+
+/// Source of the name resolution configuration of the build environment
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsMode {
+    /// The public `8.8.8.8` resolver
+    #[default]
+    Default,
+    /// The resolvers of the host system
+    Host,
+    /// The `nameservers` and `search` options
+    Custom,
+}
+
+#[derive(Deserialize, Default)]
+pub struct DnsConfiguration {
+    #[serde(default)]
+    mode: DnsMode,
+    #[serde(default)]
+    nameservers: Vec<String>,
+    #[serde(default)]
+    search: Vec<String>,
+    #[serde(default)]
+    hosts: BTreeMap<String, String>,
+}
+
+impl DnsConfiguration {
+    fn validate(&self) -> Result<(), Error> {
+        match self.mode {
+            DnsMode::Custom if self.nameservers.is_empty() => {
+                return Err(Error {
+                    message: "`dns.mode: custom` requires the `dns.nameservers` option".into(),
+                })
+            }
+            DnsMode::Default | DnsMode::Host
+                if !self.nameservers.is_empty() || !self.search.is_empty() =>
+            {
+                return Err(Error {
+                    message: "`dns.nameservers` and `dns.search` options \
+                        require `dns.mode: custom`"
+                        .into(),
+                })
+            }
+            _ => {}
+        }
+
+        let addresses = self.nameservers.iter().chain(self.hosts.values());
+        for a in addresses {
+            if a.parse::<IpAddr>().is_err() {
+                return Err(Error {
+                    message: format!("`dns` option contains invalid IP address `{}`", a),
+                });
+            }
+        }
+
+        let names = self.search.iter().chain(self.hosts.keys());
+        for n in names {
+            if n.is_empty() || n.contains(char::is_whitespace) {
+                return Err(Error {
+                    message: format!("`dns` option contains invalid host name `{}`", n),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn mode(&self) -> DnsMode {
+        self.mode
+    }
+
+    pub fn nameservers(&self) -> &Vec<String> {
+        &self.nameservers
+    }
+
+    pub fn search(&self) -> &Vec<String> {
+        &self.search
+    }
+
+    /// Extra `/etc/hosts` entries, addresses by host name
+    pub fn hosts(&self) -> &BTreeMap<String, String> {
+        &self.hosts
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct SandboxConfiguration {
+    #[serde(default)]
+    hardened: bool,
+    #[serde(default)]
+    kvm: bool,
+    tmpfs_size: Option<Size>,
+    #[serde(default)]
+    mounts: Vec<MountConfiguration>,
+}
+
+impl SandboxConfiguration {
+    fn validate(&self) -> Result<(), Error> {
+        if self.kvm && !self.hardened {
+            return Err(Error {
+                message: "`sandbox.kvm` option requires `sandbox.hardened: true`, \
+                    the host `/dev` is used otherwise"
+                    .into(),
+            });
+        }
+
+        if let Some(s) = &self.tmpfs_size {
+            if s.bytes().is_none_or(|b| b == 0) {
+                return Err(Error {
+                    message: "`sandbox.tmpfs_size` option must be a positive size, e.g. `4G`"
+                        .into(),
+                });
+            }
+        }
+
+        for m in &self.mounts {
+            let is_valid = m.target.is_absolute()
+                && m.target.components().all(|c| c != Component::ParentDir)
+                && m.target.parent().is_some();
+            if !is_valid {
+                return Err(Error {
+                    message: format!(
+                        "`sandbox.mounts` target `{}` must be an absolute path below `/` \
+                            without `..` components",
+                        m.target.display()
+                    ),
+                });
+            }
+
+            if m.source.as_os_str().is_empty() {
+                return Err(Error {
+                    message: format!(
+                        "`sandbox.mounts` entry for `{}` has an empty source path",
+                        m.target.display()
+                    ),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the build environment gets a minimal `/dev`, `/proc` and `/sys`
+    pub fn hardened(&self) -> bool {
+        self.hardened
+    }
+
+    /// Whether `/dev/kvm` is passed through to the hardened build environment
+    pub fn kvm(&self) -> bool {
+        self.kvm
+    }
+
+    /// Size in bytes of the tmpfs mounted at `/tmp`
+    pub fn tmpfs_size(&self) -> Option<u64> {
+        self.tmpfs_size.as_ref().and_then(|s| s.bytes())
+    }
+
+    /// Host paths bind-mounted into the build environment, in order
+    pub fn mounts(&self) -> &Vec<MountConfiguration> {
+        &self.mounts
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MountConfiguration {
+    source: PathBuf,
+    target: PathBuf,
+    #[serde(default)]
+    read_only: bool,
+}
+
+impl MountConfiguration {
+    /// Path on the host
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    /// Absolute path in the build environment
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FlakeConfiguration {
+    path: PathBuf,
+    configuration: String,
+}
+
+impl FlakeConfiguration {
+    fn validate(&self) -> Result<(), Error> {
+        if self.configuration.is_empty() || self.configuration.contains(['#', '"']) {
+            return Err(Error {
+                message: format!(
+                    "`flake.configuration` option contains invalid name `{}`",
+                    self.configuration
+                ),
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn configuration(&self) -> &str {
+        &self.configuration
+    }
+}
+
+/// A Nix store directory kept across builds
+#[derive(Deserialize)]
+pub struct NixStoreConfiguration {
+    path: PathBuf,
+    max_size: Option<Size>,
+}
+
+impl NixStoreConfiguration {
+    fn validate(&self) -> Result<(), Error> {
+        if self.path.as_os_str().is_empty() {
+            return Err(Error {
+                message: "`nix_store.path` option must not be empty".into(),
+            });
+        }
+
+        if let Some(s) = &self.max_size {
+            if s.bytes().is_none_or(|b| b == 0) {
+                return Err(Error {
+                    message: "`nix_store.max_size` option must be a positive size, e.g. `50G`"
+                        .into(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Host directory mounted at `/nix` in the build environment
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Size in bytes the `gc` command shrinks the store to
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size.as_ref().and_then(|s| s.bytes())
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct NixpkgsConfiguration {
+    channel: Option<String>,
+    url: Option<String>,
+    sha256: Option<String>,
+}
+
+impl NixpkgsConfiguration {
+    fn validate(&self) -> Result<(), Error> {
+        if self.channel.is_some() && self.url.is_some() {
+            return Err(Error {
+                message: "Configuration file contains both `nixpkgs.channel` \
+                                and `nixpkgs.url` options"
+                    .into(),
+            });
+        }
+
+        if let Some(c) = &self.channel {
+            if c.is_empty() || c.contains('/') {
+                return Err(Error {
+                    message: format!("`nixpkgs.channel` option contains invalid channel `{}`", c),
+                });
+            }
+        }
+
+        if let Some(u) = &self.url {
+            if !u.starts_with("https://") {
+                return Err(Error {
+                    message: format!("`nixpkgs.url` option contains non-HTTPS URL `{}`", u),
+                });
+            }
+
+            if self.sha256.is_none() {
+                return Err(Error {
+                    message: "`nixpkgs.url` option requires the `nixpkgs.sha256` option".into(),
+                });
+            }
+        }
+
+        if let Some(c) = &self.sha256 {
+            if self.url.is_none() {
+                return Err(Error {
+                    message: "`nixpkgs.sha256` option requires the `nixpkgs.url` option".into(),
+                });
+            }
+
+            if c.len() != 64 || !c.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(Error {
+                    message: "`nixpkgs.sha256` option must be a hex-encoded SHA-256 checksum"
+                        .into(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn channel(&self) -> &str {
+        match &self.channel {
+            Some(c) => c,
+            None => "nixpkgs-unstable",
+        }
+    }
+
+    pub fn url(&self) -> &Option<String> {
+        &self.url
+    }
+
+    pub fn sha256(&self) -> Option<String> {
+        self.sha256.as_ref().map(|c| c.to_lowercase())
+    }
+}
+
+const DEFAULT_ALPINE_MIRROR: &str = "https://dl-cdn.alpinelinux.org/alpine";
+const DEFAULT_NIXPKGS_MIRROR: &str = "https://nixos.org/channels";
+
+#[derive(Deserialize, Default)]
+pub struct MirrorsConfiguration {
+    alpine: Option<Vec<String>>,
+    nixpkgs: Option<Vec<String>>,
+}
+
+impl MirrorsConfiguration {
+    fn validate(&self) -> Result<(), Error> {
+        for (name, mirrors) in [("alpine", &self.alpine), ("nixpkgs", &self.nixpkgs)] {
+            let mirrors = match mirrors {
+                Some(m) => m,
+                None => continue,
+            };
+
+            if mirrors.is_empty() {
+                return Err(Error {
+                    message: format!("`mirrors.{}` option must list at least one mirror", name),
+                });
+            }
+
+            if let Some(m) = mirrors.iter().find(|m| !m.starts_with("https://")) {
+                return Err(Error {
+                    message: format!(
+                        "`mirrors.{}` option contains non-HTTPS mirror `{}`",
+                        name, m
+                    ),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn alpine(&self) -> Vec<String> {
+        mirror_list(&self.alpine, DEFAULT_ALPINE_MIRROR)
+    }
+
+    pub fn nixpkgs(&self) -> Vec<String> {
+        mirror_list(&self.nixpkgs, DEFAULT_NIXPKGS_MIRROR)
+    }
+}
+
+fn mirror_list(mirrors: &Option<Vec<String>>, default: &str) -> Vec<String> {
+    match mirrors {
+        Some(m) => m
+            .iter()
+            .map(|m| m.trim_end_matches('/').to_owned())
+            .collect(),
+        None => vec![default.to_owned()],
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct AlpineConfiguration {
+    branch: Option<String>,
+    version: Option<String>,
+    tarball: Option<PathBuf>,
+    sha512: Option<String>,
+    verify_signature: Option<bool>,
+    signing_key: Option<PathBuf>,
+    signing_key_fingerprint: Option<String>,
+}
+
+impl AlpineConfiguration {
+    fn validate(&self) -> Result<(), Error> {
+        if let Some(c) = &self.sha512 {
+            if c.len() != 128 || !c.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(Error {
+                    message: "`alpine.sha512` option must be a hex-encoded SHA-512 checksum".into(),
+                });
+            }
+        }
+
+        if let Some(b) = &self.branch {
+            if b.is_empty() || b.contains('/') {
+                return Err(Error {
+                    message: format!("`alpine.branch` option contains invalid branch `{}`", b),
+                });
+            }
+        }
+
+        if let Some(v) = &self.version {
+            if v.split('.').count() < 2
+                || !v
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_')
+            {
+                return Err(Error {
+                    message: format!("`alpine.version` option contains invalid version `{}`", v),
+                });
+            }
+        }
+
+        if self.tarball.is_some() && (self.branch.is_some() || self.version.is_some()) {
+            return Err(Error {
+                message: "`alpine.tarball` option can't be combined with \
+                    `alpine.branch` and `alpine.version` options"
+                    .into(),
+            });
+        }
+
+        if let Some(f) = &self.signing_key_fingerprint {
+            let f: String = f.chars().filter(|c| !c.is_whitespace()).collect();
+            if f.len() != 40 || !f.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(Error {
+                    message: "`alpine.signing_key_fingerprint` option must be \
+                        a hex-encoded OpenPGP v4 key fingerprint"
+                        .into(),
+                });
+            }
+        }
+
+        if self.tarball.is_some() && self.sha512.is_none() {
+            return Err(Error {
+                message: "`alpine.tarball` option requires the `alpine.sha512` option".into(),
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn branch(&self) -> &Option<String> {
+        &self.branch
+    }
+
+    pub fn version(&self) -> &Option<String> {
+        &self.version
+    }
+
+    pub fn tarball(&self) -> &Option<PathBuf> {
+        &self.tarball
+    }
+
+    pub fn sha512(&self) -> Option<String> {
+        self.sha512.as_ref().map(|c| c.to_lowercase())
+    }
+
+    /// Whether the signature of downloaded tarballs is verified
+    pub fn verify_signature(&self) -> bool {
+        self.verify_signature.unwrap_or(true)
+    }
+
+    pub fn signing_key(&self) -> &Option<PathBuf> {
+        &self.signing_key
+    }
+
+    pub fn signing_key_fingerprint(&self) -> &Option<String> {
+        &self.signing_key_fingerprint
+    }
 }
 
 #[derive(Debug)]
@@ -33,37 +769,244 @@ impl Configuration {
         let path = path.as_ref();
         let conf_file = open_config_file(path)?;
         let conf = parse_config_file(conf_file)?;
-        conf.validate()?;
 
         Ok(conf)
     }
 
-    fn validate(&self) -> Result<(), Error> {
-        if let Some(_) = self.nix_configuration {
-            if let Some(_) = self.nix_configuration_path {
+    /// Checks that the images to build are listed, unlike `validate`
+    pub fn validate_output_formats(&self) -> Result<(), Error> {
+        if self.output_formats().is_empty() {
+            return Err(Error {
+                message: "Configuration file doesn't specify any output format".into(),
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.verbose && self.quiet {
+            return Err(Error {
+                message: "`verbose` and `quiet` options are mutually exclusive".into(),
+            });
+        }
+
+        if let Some(f) = &self.log_format {
+            if log::Format::from_name(f).is_none() {
+                return Err(Error {
+                    message: format!("Unknown log format `{}`", f),
+                });
+            }
+        }
+
+        if self.output_format.is_some() && self.output_formats.is_some() {
+            return Err(Error {
+                message: "Configuration file contains both `output_format` \
+                                and `output_formats` options"
+                    .into(),
+            });
+        }
+
+        let formats = self.output_formats();
+        for (i, f) in formats.iter().enumerate() {
+            if formats[..i].contains(f) {
+                return Err(Error {
+                    message: format!(
+                        "Output format `{}` is listed more than once",
+                        f.to_string_lossy()
+                    ),
+                });
+            }
+        }
+
+        let sources = [
+            self.nix_configuration.is_some(),
+            self.nix_configuration_path.is_some(),
+            self.nix_configuration_dir.is_some(),
+        ];
+        if sources.iter().filter(|s| **s).count() > 1 {
+            return Err(Error {
+                message: "Configuration file contains more than one of `nix_configuration`, \
+                                `nix_configuration_path` and `nix_configuration_dir` options"
+                    .into(),
+            });
+        }
+
+        if let Some(e) = &self.nix_configuration_entry {
+            if self.nix_configuration_dir.is_none() {
                 return Err(Error {
-                    message: "Configuration file contains both `nix_configuration`\
-                                    and `nix_configuration_path` options"
+                    message: "`nix_configuration_entry` option requires \
+                                    the `nix_configuration_dir` option"
                         .into(),
                 });
             }
+
+            let is_relative = e
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_)));
+            if !is_relative || e.as_os_str().is_empty() {
+                return Err(Error {
+                    message: format!(
+                        "`nix_configuration_entry` option must be a relative path \
+                                    inside the configuration directory, got `{}`",
+                        e.display()
+                    ),
+                });
+            }
+        }
+
+        self.alpine.validate()?;
+        self.mirrors.validate()?;
+        self.nixpkgs.validate()?;
+        self.timeouts.validate()?;
+        self.limits.validate()?;
+        self.dns.validate()?;
+        self.sandbox.validate()?;
+
+        if let Some(f) = &self.flake {
+            if self.has_nix_configuration() {
+                return Err(Error {
+                    message: "`flake` option can't be combined with `nix_configuration`, \
+                                    `nix_configuration_path` and `nix_configuration_dir` options"
+                        .into(),
+                });
+            }
+
+            f.validate()?;
+        }
+
+        if let Some(n) = &self.nix_store {
+            if self.snapshot {
+                return Err(Error {
+                    message: "`snapshot` option can't be combined with `nix_store` option, \
+                        snapshots don't include the persistent store"
+                        .into(),
+                });
+            }
+
+            n.validate()?;
+        }
+
+        if self.snapshot {
+            for m in self.sandbox.mounts() {
+                let target = m.target();
+                if SNAPSHOT_STATE_PATHS.iter().any(|p| target.starts_with(p)) {
+                    return Err(Error {
+                        message: format!(
+                            "`snapshot` option can't be combined with `sandbox.mounts` target `{}`, \
+                                snapshots don't include what is mounted at `/nix` and `/root`",
+                            target.display()
+                        ),
+                    });
+                }
+            }
         }
 
         Ok({})
     }
 
+    pub fn alpine(&self) -> &AlpineConfiguration {
+        &self.alpine
+    }
+
+    pub fn mirrors(&self) -> &MirrorsConfiguration {
+        &self.mirrors
+    }
+
+    pub fn nixpkgs(&self) -> &NixpkgsConfiguration {
+        &self.nixpkgs
+    }
+
+    pub fn http(&self) -> &HttpConfiguration {
+        &self.http
+    }
+
+    pub fn timeouts(&self) -> &TimeoutsConfiguration {
+        &self.timeouts
+    }
+
+    pub fn limits(&self) -> &LimitsConfiguration {
+        &self.limits
+    }
+
+    pub fn dns(&self) -> &DnsConfiguration {
+        &self.dns
+    }
+
+    pub fn sandbox(&self) -> &SandboxConfiguration {
+        &self.sandbox
+    }
+
     pub fn output_path(&self) -> &Option<PathBuf> {
         &self.output_path
     }
 
-    pub fn output_format(&self) -> OsString {
-        OsString::from(&self.output_format)
+    pub fn set_output_path<P: Into<PathBuf>>(&mut self, path: P) {
+        self.output_path = Some(path.into());
+    }
+
+    pub fn output_formats(&self) -> Vec<OsString> {
+        if let Some(f) = &self.output_format {
+            return vec![OsString::from(f)];
+        }
+
+        match &self.output_formats {
+            Some(f) => f.iter().map(OsString::from).collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn set_output_formats(&mut self, formats: Vec<String>) {
+        self.output_format = None;
+        self.output_formats = Some(formats);
+    }
+
+    pub fn verbose(&self) -> bool {
+        self.verbose
+    }
+
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    pub fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
     }
 
     pub fn temporary_dir(&self) -> &Option<PathBuf> {
         &self.temporary_dir
     }
 
+    pub fn cache_dir(&self) -> &Option<PathBuf> {
+        &self.cache_dir
+    }
+
+    /// Whether the bootstrapped build environment is cached and reused
+    pub fn snapshot(&self) -> bool {
+        self.snapshot
+    }
+
+    pub fn log_dir(&self) -> &Option<PathBuf> {
+        &self.log_dir
+    }
+
+    /// Either `text` (default) or `json`
+    pub fn log_format(&self) -> &str {
+        self.log_format.as_deref().unwrap_or("text")
+    }
+
+    pub fn set_log_format(&mut self, format: String) {
+        self.log_format = Some(format);
+    }
+
+    pub fn set_temporary_dir<P: Into<PathBuf>>(&mut self, path: P) {
+        self.temporary_dir = Some(path.into());
+    }
+
     pub fn nix_configuration_path(&self) -> &Option<PathBuf> {
         &self.nix_configuration_path
     }
@@ -72,6 +1015,26 @@ impl Configuration {
         &self.nix_configuration
     }
 
+    pub fn nix_configuration_dir(&self) -> &Option<PathBuf> {
+        &self.nix_configuration_dir
+    }
+
+    /// Path of the entry point file relative to `nix_configuration_dir`
+    pub fn nix_configuration_entry(&self) -> PathBuf {
+        match &self.nix_configuration_entry {
+            Some(e) => e.to_owned(),
+            None => PathBuf::from("configuration.nix"),
+        }
+    }
+
+    pub fn flake(&self) -> &Option<FlakeConfiguration> {
+        &self.flake
+    }
+
+    pub fn nix_store(&self) -> &Option<NixStoreConfiguration> {
+        &self.nix_store
+    }
+
     pub fn has_nix_configuration(&self) -> bool {
         if let Some(_) = self.nix_configuration {
             return true;
@@ -81,6 +1044,10 @@ impl Configuration {
             return true;
         }
 
+        if self.nix_configuration_dir.is_some() {
+            return true;
+        }
+
         false
     }
 }
@@ -110,3 +1077,51 @@ fn parse_config_file(file: File) -> Result<Configuration, Error> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(yaml: &str) -> Option<u64> {
+        serde_yaml::from_str::<Size>(yaml).unwrap().bytes()
+    }
+
+    fn snapshot_with_mount(target: &str) -> Configuration {
+        let yaml = format!(
+            "snapshot: true\nsandbox:\n  mounts:\n    - source: /scratch\n      target: {}\n",
+            target
+        );
+
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    #[test]
+    fn size_accepts_numbers_and_binary_suffixes() {
+        assert_eq!(size("4096"), Some(4096));
+        assert_eq!(size("'4096'"), Some(4096));
+        assert_eq!(size("512K"), Some(512 << 10));
+        assert_eq!(size("512m"), Some(512 << 20));
+        assert_eq!(size("' 8 G '"), Some(8 << 30));
+        assert_eq!(size("2T"), Some(2 << 40));
+    }
+
+    #[test]
+    fn size_rejects_invalid_values() {
+        for yaml in ["''", "G", "1.5G", "-1K", "8GB", "16E", "20000000T"] {
+            assert_eq!(size(yaml), None, "`{}` was accepted", yaml);
+        }
+    }
+
+    #[test]
+    fn snapshot_rejects_mounts_over_bootstrapped_state() {
+        for target in ["/nix", "/nix/store", "/root", "/root/.nix-channels"] {
+            let conf = snapshot_with_mount(target);
+            assert!(conf.validate().is_err(), "`{}` was accepted", target);
+        }
+
+        for target in ["/nixos", "/run/secrets", "/scratch"] {
+            let conf = snapshot_with_mount(target);
+            assert!(conf.validate().is_ok(), "`{}` was rejected", target);
+        }
+    }
+}
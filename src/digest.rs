@@ -0,0 +1,18 @@
+use sha2::Digest;
+use std::{
+    fs::File,
+    io::{copy, Result, Write},
+    path::Path,
+};
+
+/// Returns the size and the hex-encoded `D` digest of the `path` file
+pub fn hash_file<D: Digest + Write>(path: &Path) -> Result<(u64, String)> {
+    let mut hasher = D::new();
+    let size = copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok((size, to_hex(&hasher.finalize())))
+}
+
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,172 @@
+use crate::{
+    alpine::SignatureVerification,
+    config::Configuration,
+    gpg,
+    log::{self, Level},
+    process::{run_forked, run_host_command},
+};
+use nix::sched::{unshare, CloneFlags};
+use std::path::Path;
+use tempdir::TempDir;
+
+macro_rules! ok {
+    ($($msg:expr),+) => {
+        log::message(Level::Ok, &format!($($msg),+))
+    }
+}
+
+macro_rules! warn {
+    ($($msg:expr),+) => {
+        log::message(Level::Warning, &format!($($msg),+))
+    }
+}
+
+macro_rules! fail {
+    ($($msg:expr),+) => {{
+        log::message(Level::Error, &format!($($msg),+));
+
+        return false
+    }};
+}
+
+pub fn run(conf: &Configuration) -> Result<(), ()> {
+    log::info("Checking the host system...");
+
+    let checks: [fn(&Configuration) -> bool; 5] = [
+        check_user_namespace_sysctls,
+        check_namespace_creation,
+        check_temporary_dir,
+        check_gpg,
+        check_kvm,
+    ];
+
+    // run every check, even if some of them failed, to report all problems at once
+    let failed = checks.iter().filter(|check| !check(conf)).count();
+    if failed > 0 {
+        log::message(
+            Level::Failure,
+            &format!("{} check(s) failed, builds are unlikely to succeed", failed),
+        );
+
+        return Err(());
+    }
+
+    log::info("The host system is able to run builds.");
+
+    Ok(())
+}
+
+fn read_sysctl<P: AsRef<Path>>(path: P) -> Option<i64> {
+    match std::fs::read_to_string(path) {
+        Ok(v) => v.trim().parse().ok(),
+        Err(_) => None,
+    }
+}
+
+fn check_user_namespace_sysctls(_: &Configuration) -> bool {
+    if let Some(0) = read_sysctl("/proc/sys/kernel/unprivileged_userns_clone") {
+        fail!("unprivileged user namespaces are disabled by `kernel.unprivileged_userns_clone`");
+    }
+
+    if let Some(0) = read_sysctl("/proc/sys/user/max_user_namespaces") {
+        fail!("user namespaces are disabled by `user.max_user_namespaces`");
+    }
+
+    if let Some(1) = read_sysctl("/proc/sys/kernel/apparmor_restrict_unprivileged_userns") {
+        warn!("AppArmor restricts unprivileged user namespaces, the build may be denied");
+    }
+
+    ok!("user namespaces are enabled");
+
+    true
+}
+
+fn check_namespace_creation(_: &Configuration) -> bool {
+    let result = run_forked(|| {
+        match unshare(
+            CloneFlags::CLONE_NEWUSER
+                | CloneFlags::CLONE_NEWNS
+                | CloneFlags::CLONE_NEWPID
+                | CloneFlags::CLONE_NEWUTS
+                | CloneFlags::CLONE_NEWIPC,
+        ) {
+            Ok(_) => None,
+            Err(e) => Some(format!("{}", e)),
+        }
+    });
+
+    match result {
+        Ok(None) => ok!("successfully created a private namespace"),
+        Ok(Some(e)) => fail!("failed to create a private namespace: {}", e),
+        Err(e) => fail!("failed to test the namespace creation: {}", e),
+    }
+
+    true
+}
+
+fn check_temporary_dir(conf: &Configuration) -> bool {
+    let result = match conf.temporary_dir() {
+        Some(t) => TempDir::new_in(t, "nixosconj-doctor"),
+        None => TempDir::new("nixosconj-doctor"),
+    };
+
+    match result {
+        Ok(d) => ok!(
+            "temporary directory `{}` is writable",
+            d.path().parent().unwrap_or(d.path()).display()
+        ),
+        Err(e) => fail!("failed to create a temporary directory: {}", e),
+    }
+
+    true
+}
+
+fn check_gpg(conf: &Configuration) -> bool {
+    if !conf.alpine().verify_signature() {
+        ok!("signature verification is disabled, `gpg` is not required");
+
+        return true;
+    }
+
+    match run_host_command("gpg", ["--version"]) {
+        Ok(o) if o.status.success() => ok!("`gpg` is available"),
+        _ => fail!("`gpg` is required to verify the Alpine tarball signature"),
+    }
+
+    let alpine = conf.alpine();
+    let verification = SignatureVerification::new(
+        alpine.signing_key().clone(),
+        alpine
+            .signing_key_fingerprint()
+            .as_deref()
+            .map(gpg::normalize_fingerprint),
+    );
+
+    let dir = match TempDir::new("nixosconj-doctor") {
+        Ok(d) => d,
+        Err(e) => fail!("failed to create a temporary directory: {}", e),
+    };
+
+    match verification.check_key(dir.path()) {
+        Ok(f) => ok!("the Alpine signing key `{}` is valid", f),
+        Err(e) => fail!("{}", e),
+    }
+
+    true
+}
+
+fn check_kvm(_: &Configuration) -> bool {
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/kvm")
+    {
+        Ok(_) => ok!("`/dev/kvm` is available"),
+        Err(e) => warn!(
+            "`/dev/kvm` is not usable ({}), VM-based image formats will be slow",
+            e
+        ),
+    }
+
+    true
+}
@@ -0,0 +1,173 @@
+use crate::process::run_host_command;
+use std::{ffi::OsStr, fmt::Display, path::Path};
+use tempdir::TempDir;
+
+#[derive(Debug)]
+pub struct Error {
+    message: String,
+}
+
+impl Error {
+    pub fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Verifies the detached `signature` of `file` by `key` in a throwaway GnuPG home
+pub fn verify_detached(
+    key: &Path,
+    signature: &Path,
+    file: &Path,
+    fingerprint: Option<&str>,
+) -> Result<String, Error> {
+    let home = match TempDir::new("nixosconj-gnupg") {
+        Ok(h) => h,
+        Err(e) => {
+            return Err(Error::new(format!(
+                "failed to create a temporary GnuPG home directory: {}",
+                e
+            )))
+        }
+    };
+
+    let homedir = home.path().as_os_str();
+    let import = run_host_command(
+        "gpg",
+        [
+            OsStr::new("--batch"),
+            OsStr::new("--homedir"),
+            homedir,
+            OsStr::new("--import"),
+            key.as_os_str(),
+        ],
+    );
+    match import {
+        Ok(o) if o.status.success() => {}
+        Ok(o) => {
+            return Err(Error::new(format!(
+                "failed to import the signing key `{}`: {}",
+                key.display(),
+                o.tail
+            )))
+        }
+        Err(e) => return Err(Error::new(format!("{}", e))),
+    }
+
+    let verify = run_host_command(
+        "gpg",
+        [
+            OsStr::new("--batch"),
+            OsStr::new("--homedir"),
+            homedir,
+            OsStr::new("--status-fd"),
+            OsStr::new("1"),
+            OsStr::new("--verify"),
+            signature.as_os_str(),
+            file.as_os_str(),
+        ],
+    );
+    let output = match verify {
+        Ok(o) => o,
+        Err(e) => return Err(Error::new(format!("{}", e))),
+    };
+
+    // [GNUPG:] VALIDSIG <fingerprint> <date> ... <primary-key-fingerprint>
+    let status = String::from_utf8_lossy(&output.stdout);
+    let valid_fingerprints: Vec<&str> = status
+        .lines()
+        .filter_map(|l| l.strip_prefix("[GNUPG:] VALIDSIG "))
+        .flat_map(|l| {
+            let fields: Vec<&str> = l.split_whitespace().collect();
+            [fields.first().copied(), fields.last().copied()]
+        })
+        .flatten()
+        .collect();
+
+    if !output.status.success() || valid_fingerprints.is_empty() {
+        return Err(Error::new(format!(
+            "signature `{}` is not valid: {}",
+            signature.display(),
+            output.tail
+        )));
+    }
+
+    match fingerprint {
+        Some(expected) => {
+            let expected = normalize_fingerprint(expected);
+            match valid_fingerprints.iter().find(|f| **f == expected) {
+                Some(f) => Ok(f.to_string()),
+                None => Err(Error::new(format!(
+                    "signature `{}` was made by key `{}` instead of the expected `{}`",
+                    signature.display(),
+                    valid_fingerprints[0],
+                    expected
+                ))),
+            }
+        }
+        None => Ok(valid_fingerprints[0].to_owned()),
+    }
+}
+
+/// Returns the fingerprints of the keys in the `key` file without importing them
+pub fn key_fingerprints(key: &Path) -> Result<Vec<String>, Error> {
+    let home = match TempDir::new("nixosconj-gnupg") {
+        Ok(h) => h,
+        Err(e) => {
+            return Err(Error::new(format!(
+                "failed to create a temporary GnuPG home directory: {}",
+                e
+            )))
+        }
+    };
+
+    let show = run_host_command(
+        "gpg",
+        [
+            OsStr::new("--batch"),
+            OsStr::new("--homedir"),
+            home.path().as_os_str(),
+            OsStr::new("--with-colons"),
+            OsStr::new("--show-keys"),
+            key.as_os_str(),
+        ],
+    );
+    let output = match show {
+        Ok(o) => o,
+        Err(e) => return Err(Error::new(format!("{}", e))),
+    };
+
+    // fpr:::::::::<fingerprint>:
+    let listing = String::from_utf8_lossy(&output.stdout);
+    let fingerprints: Vec<String> = listing
+        .lines()
+        .filter_map(|l| l.strip_prefix("fpr:"))
+        .filter_map(|l| l.split(':').nth(8))
+        .map(|f| f.to_owned())
+        .collect();
+
+    if !output.status.success() || fingerprints.is_empty() {
+        return Err(Error::new(format!(
+            "`{}` holds no valid OpenPGP key: {}",
+            key.display(),
+            output.tail
+        )));
+    }
+
+    Ok(fingerprints)
+}
+
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase()
+}
@@ -1,12 +1,35 @@
-use bytes::Buf;
+use crate::{
+    digest::to_hex,
+    log::Progress,
+    process::{current_deadline, is_cancelled},
+};
 use core::time::Duration;
-use reqwest::{IntoUrl, Url};
-use std::io::Read;
+use reqwest::{
+    blocking::RequestBuilder,
+    header::{HeaderMap, CONTENT_RANGE, RANGE},
+    Certificate, IntoUrl, NoProxy, Proxy, StatusCode, Url,
+};
+use sha2::Digest;
+use std::{
+    fs::File,
+    io::{copy, BufWriter, Result as IoResult, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    thread::sleep,
+};
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// Upper bound for the delay between retries
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
 pub struct ClientBuilder {
     builder: reqwest::blocking::ClientBuilder,
+    retries: u32,
+    retry_backoff: Duration,
+    request_timeout: Option<Duration>,
+    https_proxy: Option<String>,
+    no_proxy: Option<String>,
+    ca_bundle: Option<PathBuf>,
 }
 
 impl ClientBuilder {
@@ -17,6 +40,12 @@ impl ClientBuilder {
                 .referer(false)
                 .use_rustls_tls()
                 .https_only(true),
+            retries: 0,
+            retry_backoff: Duration::from_secs(1),
+            request_timeout: None,
+            https_proxy: None,
+            no_proxy: None,
+            ca_bundle: None,
         }
     }
 
@@ -26,50 +55,280 @@ impl ClientBuilder {
         self
     }
 
+    /// Limit of a whole request, shortened to the time left to the run
     pub fn request_timeout<T: Into<Option<Duration>>>(mut self, timeout: T) -> Self {
-        self.builder = self.builder.timeout(timeout);
+        self.request_timeout = timeout.into();
+
+        self
+    }
+
+    /// Retries on connection and server errors before moving on to the next mirror
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+
+        self
+    }
+
+    /// Initial delay between retries, doubled after every attempt
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.retry_backoff = backoff;
+
+        self
+    }
+
+    /// Proxy for the requests, which are all made over HTTPS
+    pub fn https_proxy(mut self, proxy: Option<String>) -> Self {
+        self.https_proxy = proxy;
+
+        self
+    }
+
+    /// Comma-separated list of hosts that bypass the proxies
+    pub fn no_proxy(mut self, no_proxy: Option<String>) -> Self {
+        self.no_proxy = no_proxy;
+
+        self
+    }
+
+    /// PEM bundle with CA certificates trusted in addition to the built-in ones
+    pub fn ca_bundle(mut self, path: Option<PathBuf>) -> Self {
+        self.ca_bundle = path;
 
         self
     }
 
     pub fn build(self) -> Result<Client> {
-        Client::new(self.builder.build()?)
+        let mut builder = self.builder;
+        let no_proxy = match &self.no_proxy {
+            Some(n) => NoProxy::from_string(n),
+            None => None,
+        };
+
+        if let Some(p) = &self.https_proxy {
+            builder = builder.proxy(Proxy::https(p)?.no_proxy(no_proxy));
+        }
+
+        if let Some(p) = &self.ca_bundle {
+            let pem = match std::fs::read(p) {
+                Ok(b) => b,
+                Err(e) => {
+                    return Err(Error::new(format!(
+                        "failed to read the CA bundle `{}`: {}",
+                        p.display(),
+                        e
+                    )))
+                }
+            };
+
+            builder = builder.add_root_certificate(Certificate::from_pem(&pem)?);
+        }
+
+        Client::new(
+            builder.build()?,
+            self.retries,
+            self.retry_backoff,
+            self.request_timeout,
+        )
     }
 }
 
+#[derive(Clone)]
 pub struct Client {
     client: reqwest::blocking::Client,
+    retries: u32,
+    retry_backoff: Duration,
+    request_timeout: Option<Duration>,
 }
 
 impl Client {
-    fn new(client: reqwest::blocking::Client) -> Result<Self> {
-        Ok(Self { client })
+    fn new(
+        client: reqwest::blocking::Client,
+        retries: u32,
+        retry_backoff: Duration,
+        request_timeout: Option<Duration>,
+    ) -> Result<Self> {
+        Ok(Self {
+            client,
+            retries,
+            retry_backoff,
+            request_timeout,
+        })
     }
 
     pub fn builder() -> ClientBuilder {
         ClientBuilder::new()
     }
 
+    /// Returns the first successful response of the request URLs, tried in order
     pub fn get(&self, req: GetRequest) -> Result<Response> {
-        let resp = self.client.get(req.url).send()?;
+        let mut errors = vec![];
+
+        for url in req.urls {
+            match self.with_retries(|| self.get_one(url.clone())) {
+                Ok(r) => return Ok(r),
+                Err(e) => errors.push(format!("{}: {}", url, e)),
+            }
+
+            if is_cancelled() || is_out_of_time() {
+                break;
+            }
+        }
+
+        Err(Error::new(errors.join("; ")))
+    }
+
+    /// Streams the response into `path`, resuming interrupted transfers with Range requests
+    pub fn download<D: Digest>(&self, req: GetRequest, path: &Path) -> Result<Download> {
+        let mut partial = PartialDownload::<D>::create(path)?;
+        let mut errors = vec![];
+
+        for url in req.urls {
+            match self.with_retries(|| self.download_one(url.clone(), &mut partial)) {
+                Ok(_) => return Ok(partial.finish()?),
+                Err(e) => errors.push(format!("{}: {}", url, e)),
+            }
+
+            if is_cancelled() || is_out_of_time() {
+                break;
+            }
+        }
+
+        Err(Error::new(errors.join("; ")))
+    }
+
+    fn with_retries<T, F: FnMut() -> Result<T>>(&self, mut f: F) -> Result<T> {
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+
+        loop {
+            match f() {
+                Ok(r) => return Ok(r),
+                Err(e) if e.retryable && attempt < self.retries && !is_cancelled() => {
+                    attempt += 1;
+                    sleep(current_deadline().map_or(backoff, |d| backoff.min(d.remaining())));
+                    backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+                }
+                Err(e) if attempt > 0 => {
+                    return Err(Error::new(format!("{} (after {} retries)", e, attempt)))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Starts a request limited by the time left to the run and its current stage
+    fn request(&self, url: Url) -> Result<RequestBuilder> {
+        let mut timeout = self.request_timeout;
+        if let Some(d) = current_deadline() {
+            if d.is_expired() {
+                return Err(Error::new(format!("{}", d.error("the request"))));
+            }
+
+            timeout = Some(timeout.map_or(d.remaining(), |t| t.min(d.remaining())));
+        }
+
+        let req = self.client.get(url);
+
+        Ok(match timeout {
+            Some(t) => req.timeout(t),
+            None => req,
+        })
+    }
+
+    fn get_one(&self, url: Url) -> Result<Response> {
+        let resp = self.request(url)?.send()?;
 
         if !resp.status().is_success() {
-            return Err(Error::new(format!("HTTP error: {}", resp.status())));
+            return Err(Error::status(resp.status()));
         }
 
         Ok(Response { inner: resp })
     }
+
+    fn download_one<D: Digest>(&self, url: Url, partial: &mut PartialDownload<D>) -> Result<()> {
+        let offset = partial.size;
+        let mut req = self.request(url)?;
+        if offset > 0 {
+            req = req.header(RANGE, format!("bytes={}-", offset));
+        }
+
+        let mut resp = req.send()?;
+        let status = resp.status();
+
+        if status == StatusCode::RANGE_NOT_SATISFIABLE && offset > 0 {
+            // the partial file doesn't match the remote one, start over
+            partial.restart()?;
+
+            return Err(Error::retryable(format!("HTTP error: {}", status)));
+        }
+
+        if !status.is_success() {
+            return Err(Error::status(status));
+        }
+
+        // the server may ignore the Range header and send the whole body
+        if status != StatusCode::PARTIAL_CONTENT || !content_range_starts_at(resp.headers(), offset)
+        {
+            partial.restart()?;
+        }
+
+        let total = resp.content_length().map(|l| partial.size + l);
+        partial.progress = Some(Progress::new(resp.url().as_str(), total));
+
+        match copy(&mut resp, partial) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::retryable(format!("transfer interrupted: {}", e))),
+        }
+    }
+}
+
+fn is_out_of_time() -> bool {
+    current_deadline().is_some_and(|d| d.is_expired())
+}
+
+fn content_range_starts_at(headers: &HeaderMap, offset: u64) -> bool {
+    let range = match headers.get(CONTENT_RANGE) {
+        Some(r) => r.to_str().unwrap_or_default(),
+        None => return false,
+    };
+
+    // Content-Range: bytes <start>-<end>/<size>
+    match range
+        .strip_prefix("bytes ")
+        .and_then(|r| r.split('-').next())
+    {
+        Some(start) => start.parse::<u64>() == Ok(offset),
+        None => false,
+    }
 }
 
 pub struct GetRequest {
-    url: Url,
+    urls: Vec<Url>,
 }
 
 impl GetRequest {
     pub fn new(u: impl IntoUrl) -> Result<Self> {
         let url = u.into_url()?;
 
-        Ok(Self { url })
+        Ok(Self { urls: vec![url] })
+    }
+
+    /// Creates a request for `path` relative to each of the `mirrors` base URLs
+    pub fn mirrored<M: AsRef<str>>(mirrors: &[M], path: &str) -> Result<Self> {
+        let mut urls = vec![];
+        for m in mirrors {
+            let url = format!("{}/{}", m.as_ref().trim_end_matches('/'), path);
+            match Url::parse(&url) {
+                Ok(u) => urls.push(u),
+                Err(e) => return Err(Error::new(format!("invalid URL `{}`: {}", url, e))),
+            }
+        }
+
+        if urls.is_empty() {
+            return Err(Error::new("no mirrors to download from".to_owned()));
+        }
+
+        Ok(Self { urls })
     }
 }
 
@@ -81,26 +340,138 @@ impl Response {
     pub fn as_text(self) -> Result<String> {
         Ok(self.inner.text()?)
     }
+}
+
+/// Describes a response body saved to a file
+pub struct Download {
+    size: u64,
+    digest: String,
+}
+
+impl Download {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
 
-    pub fn as_reader(self) -> Result<impl Read> {
-        Ok(self.inner.bytes()?.reader())
+    /// Hex-encoded digest of the downloaded content
+    pub fn digest(&self) -> &str {
+        &self.digest
+    }
+}
+
+/// A file being downloaded, hashed as its content is written
+struct PartialDownload<D: Digest> {
+    file: BufWriter<File>,
+    hasher: D,
+    size: u64,
+    progress: Option<Progress>,
+}
+
+impl<D: Digest> PartialDownload<D> {
+    fn create(path: &Path) -> IoResult<Self> {
+        let file = File::create(path)?;
+
+        Ok(Self {
+            file: BufWriter::new(file),
+            hasher: D::new(),
+            size: 0,
+            progress: None,
+        })
+    }
+
+    fn restart(&mut self) -> IoResult<()> {
+        self.file.flush()?;
+        self.file.get_mut().set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.hasher = D::new();
+        self.size = 0;
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> IoResult<Download> {
+        self.file.flush()?;
+        if let Some(p) = &mut self.progress {
+            p.report(self.size);
+        }
+
+        let digest = to_hex(&self.hasher.finalize());
+
+        Ok(Download {
+            size: self.size,
+            digest,
+        })
+    }
+}
+
+impl<D: Digest> Write for PartialDownload<D> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        if is_cancelled() {
+            return Err(std::io::Error::other("the run was cancelled"));
+        }
+
+        let written = self.file.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        if let Some(p) = &mut self.progress {
+            p.update(self.size);
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.file.flush()
     }
 }
 
 #[derive(Debug)]
 pub struct Error {
     error: String,
+    retryable: bool,
 }
 
 impl From<reqwest::Error> for Error {
     fn from(error: reqwest::Error) -> Self {
+        let retryable = error.is_connect() || error.is_timeout() || error.is_body();
+
+        Self {
+            error: format!("{}", error),
+            retryable,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
         Error::new(format!("{}", error))
     }
 }
 
 impl Error {
     fn new(error: String) -> Self {
-        Self { error }
+        Self {
+            error,
+            retryable: false,
+        }
+    }
+
+    fn retryable(error: String) -> Self {
+        Self {
+            error,
+            retryable: true,
+        }
+    }
+
+    fn status(status: StatusCode) -> Self {
+        let retryable = status.is_server_error()
+            || status == StatusCode::REQUEST_TIMEOUT
+            || status == StatusCode::TOO_MANY_REQUESTS;
+
+        Self {
+            error: format!("HTTP error: {}", status),
+            retryable,
+        }
     }
 }
 
@@ -111,3 +482,43 @@ impl std::fmt::Display for Error {
 }
 
 impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(content_range: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(r) = content_range {
+            headers.insert(CONTENT_RANGE, r.parse().unwrap());
+        }
+
+        headers
+    }
+
+    #[test]
+    fn content_range_must_start_at_the_offset() {
+        assert!(content_range_starts_at(
+            &headers(Some("bytes 100-199/200")),
+            100
+        ));
+        assert!(content_range_starts_at(&headers(Some("bytes 0-99/*")), 0));
+
+        assert!(!content_range_starts_at(
+            &headers(Some("bytes 0-199/200")),
+            100
+        ));
+        assert!(!content_range_starts_at(
+            &headers(Some("bytes 101-199/200")),
+            100
+        ));
+    }
+
+    #[test]
+    fn content_range_must_be_a_byte_range() {
+        assert!(!content_range_starts_at(&headers(None), 0));
+        assert!(!content_range_starts_at(&headers(Some("bytes */200")), 0));
+        assert!(!content_range_starts_at(&headers(Some("items 0-9/10")), 0));
+        assert!(!content_range_starts_at(&headers(Some("bytes x-9/10")), 0));
+    }
+}
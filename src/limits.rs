@@ -0,0 +1,260 @@
+use crate::{config::LimitsConfiguration, store::disk_usage};
+use nix::{
+    sched::{sched_getaffinity, sched_setaffinity, CpuSet},
+    sys::resource::{setrlimit, Resource},
+    unistd::{getpid, Pid},
+};
+use std::{
+    fs::{create_dir, read_dir, read_to_string, remove_dir, write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Period of the `cpu.max` bandwidth limit, in microseconds
+const CPU_PERIOD: u64 = 100_000;
+
+/// Interval between the measurements of the build root for the disk limit
+pub const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Prefix of the per-run cgroups created in the current cgroup
+const RUN_CGROUP_PREFIX: &str = "nixos-conjurer.";
+
+/// Build cgroup next to the caller's leaf, as a cgroup can't hold processes and enable controllers
+pub struct Cgroup {
+    path: PathBuf,
+    /// Per-run cgroup, a child of the original cgroup of the calling process
+    run: PathBuf,
+    /// Controllers enabled in the original cgroup for the per-run one
+    enabled: Vec<String>,
+}
+
+impl Cgroup {
+    /// Creates a per-run cgroup with the `limits` applied in the current one
+    pub fn create(limits: &LimitsConfiguration) -> Result<Self, String> {
+        let current = current_cgroup()?;
+
+        let mut controllers = vec![];
+        if limits.memory().is_some() {
+            controllers.push("memory");
+        }
+        if limits.cpus().is_some() {
+            controllers.push("cpu");
+        }
+        if limits.pids().is_some() {
+            controllers.push("pids");
+        }
+
+        let available = read_file(&current.join("cgroup.controllers"))?;
+        for c in &controllers {
+            if !available.split_whitespace().any(|a| a == *c) {
+                return Err(format!(
+                    "the `{}` controller is not available in `{}`",
+                    c,
+                    current.display()
+                ));
+            }
+        }
+
+        remove_stale_run_cgroups(&current);
+
+        let run = current.join(format!("{}{}", RUN_CGROUP_PREFIX, getpid()));
+        make_dir(&run)?;
+        let mut cgroup = Self {
+            path: run.join("build"),
+            run,
+            enabled: vec![],
+        };
+
+        // from now on dropping the cgroup moves the process back
+        let main = cgroup.run.join("main");
+        make_dir(&main)?;
+        write_file(&main.join("cgroup.procs"), &getpid().to_string())?;
+
+        let enabled = read_file(&current.join("cgroup.subtree_control"))?;
+        let missing: Vec<String> = controllers
+            .iter()
+            .filter(|c| !enabled.split_whitespace().any(|e| e == **c))
+            .map(|c| c.to_string())
+            .collect();
+        if !missing.is_empty() {
+            let enable: Vec<String> = missing.iter().map(|c| format!("+{}", c)).collect();
+            if let Err(e) = write_file(&current.join("cgroup.subtree_control"), &enable.join(" ")) {
+                return Err(format!(
+                    "{}, `{}` must contain no other processes",
+                    e,
+                    current.display()
+                ));
+            }
+            cgroup.enabled = missing;
+        }
+
+        let enable: Vec<String> = controllers.iter().map(|c| format!("+{}", c)).collect();
+        write_file(
+            &cgroup.run.join("cgroup.subtree_control"),
+            &enable.join(" "),
+        )?;
+        make_dir(&cgroup.path)?;
+
+        if let Some(m) = limits.memory() {
+            write_file(&cgroup.path.join("memory.max"), &m.to_string())?;
+            // swap would let the build exceed the limit, but may be unsupported
+            let _ = write(cgroup.path.join("memory.swap.max"), "0");
+        }
+
+        if let Some(c) = limits.cpus() {
+            let quota = c as u64 * CPU_PERIOD;
+            write_file(
+                &cgroup.path.join("cpu.max"),
+                &format!("{} {}", quota, CPU_PERIOD),
+            )?;
+        }
+
+        if let Some(p) = limits.pids() {
+            write_file(&cgroup.path.join("pids.max"), &p.to_string())?;
+        }
+
+        Ok(cgroup)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Moves the calling process, and so all of its future children, into the cgroup
+    pub fn enter(&self) -> Result<(), String> {
+        write_file(&self.path.join("cgroup.procs"), &getpid().to_string())
+    }
+}
+
+impl Drop for Cgroup {
+    /// Moves the calling process back and removes the cgroups, the next run removes leftovers
+    fn drop(&mut self) {
+        let current = self.run.parent().unwrap();
+        let _ = write(current.join("cgroup.procs"), getpid().to_string());
+
+        for cgroup in [&self.path, &self.run.join("main"), &self.run] {
+            let _ = remove_dir(cgroup);
+        }
+
+        if !self.enabled.is_empty() {
+            let disable: Vec<String> = self.enabled.iter().map(|c| format!("-{}", c)).collect();
+            let _ = write(current.join("cgroup.subtree_control"), disable.join(" "));
+        }
+    }
+}
+
+/// Removes the per-run cgroups left behind by the runs that have exited
+fn remove_stale_run_cgroups(current: &Path) {
+    let entries = match read_dir(current) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let is_run = entry
+            .file_name()
+            .to_string_lossy()
+            .strip_prefix(RUN_CGROUP_PREFIX)
+            .is_some_and(|p| p.parse::<u32>().is_ok());
+        let run = entry.path();
+        let events = read_to_string(run.join("cgroup.events")).unwrap_or_default();
+        if !is_run || !events.lines().any(|l| l == "populated 0") {
+            continue;
+        }
+
+        for child in ["build", "main"] {
+            let _ = remove_dir(run.join(child));
+        }
+        let _ = remove_dir(&run);
+    }
+}
+
+fn make_dir(path: &Path) -> Result<(), String> {
+    match create_dir(path) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("failed to create `{}`: {}", path.display(), e)),
+    }
+}
+
+/// Applies the `limits` a cgroup doesn't enforce with `setrlimit` and the CPU affinity
+pub fn apply_rlimits(limits: &LimitsConfiguration, cgroup: bool) -> Result<(), String> {
+    if cgroup {
+        return Ok(());
+    }
+
+    if let Some(m) = limits.memory() {
+        set_rlimit(Resource::RLIMIT_AS, m)?;
+    }
+
+    if let Some(c) = limits.cpus() {
+        limit_cpus(c as usize)?;
+    }
+
+    Ok(())
+}
+
+/// Fails once the files in `build_root` use more than `limit` bytes
+pub fn check_disk_usage(build_root: &Path, limit: u64) -> Result<(), String> {
+    match disk_usage(build_root) {
+        Ok(used) if used > limit => Err(format!(
+            "the build root uses {} bytes, more than the {} bytes allowed by `limits.disk`",
+            used, limit
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn set_rlimit(resource: Resource, limit: u64) -> Result<(), String> {
+    match setrlimit(resource, limit, limit) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("failed to set the {:?} limit: {}", resource, e)),
+    }
+}
+
+/// Restricts the process to the first `count` of the CPUs it may run on
+fn limit_cpus(count: usize) -> Result<(), String> {
+    let current = match sched_getaffinity(Pid::from_raw(0)) {
+        Ok(c) => c,
+        Err(e) => return Err(format!("failed to get the CPU affinity: {}", e)),
+    };
+
+    let mut cpus = CpuSet::new();
+    let allowed = (0..CpuSet::count()).filter(|c| current.is_set(*c).unwrap_or(false));
+    for cpu in allowed.take(count) {
+        if let Err(e) = cpus.set(cpu) {
+            return Err(format!("failed to limit the CPUs: {}", e));
+        }
+    }
+
+    match sched_setaffinity(Pid::from_raw(0), &cpus) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("failed to set the CPU affinity: {}", e)),
+    }
+}
+
+/// Returns the cgroup v2 directory of the calling process
+fn current_cgroup() -> Result<PathBuf, String> {
+    let cgroups = read_file(Path::new("/proc/self/cgroup"))?;
+
+    // cgroup v2 is the hierarchy with ID 0 and no controllers in the name
+    match cgroups.lines().find_map(|l| l.strip_prefix("0::")) {
+        Some(p) => Ok(Path::new(CGROUP_ROOT).join(p.trim_start_matches('/'))),
+        None => Err("cgroup v2 is not used by the system".to_owned()),
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, String> {
+    match read_to_string(path) {
+        Ok(s) => Ok(s),
+        Err(e) => Err(format!("failed to read `{}`: {}", path.display(), e)),
+    }
+}
+
+fn write_file(path: &Path, content: &str) -> Result<(), String> {
+    match write(path, content) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("failed to write `{}`: {}", path.display(), e)),
+    }
+}
@@ -0,0 +1,76 @@
+use crate::process::{current_deadline, is_cancelled};
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+};
+use std::{
+    fs::{create_dir_all, File, OpenOptions},
+    os::unix::io::AsRawFd,
+    path::Path,
+    thread::sleep,
+    time::Duration,
+};
+
+/// Lock file at the top of the locked directory
+pub const LOCK_FILE: &str = ".nixos-conjurer.lock";
+
+/// Exclusive lock of a directory used by a run, released when dropped
+pub struct DirectoryLock {
+    _file: File,
+}
+
+impl DirectoryLock {
+    /// Locks the `path` directory, or returns `None` if another run holds the lock
+    pub fn try_acquire(path: &Path) -> Result<Option<Self>, String> {
+        let file = open_lock_file(path)?;
+
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(_) => Ok(Some(Self { _file: file })),
+            Err(Errno::EWOULDBLOCK) => Ok(None),
+            Err(e) => Err(format!("failed to lock `{}`: {}", path.display(), e)),
+        }
+    }
+
+    /// Waits until other runs release the `path` directory and locks it
+    pub fn wait(path: &Path) -> Result<Self, String> {
+        let file = open_lock_file(path)?;
+
+        loop {
+            match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+                Ok(_) => return Ok(Self { _file: file }),
+                Err(Errno::EWOULDBLOCK) => {}
+                Err(e) => return Err(format!("failed to lock `{}`: {}", path.display(), e)),
+            }
+
+            if is_cancelled() {
+                return Err("the run was cancelled".to_owned());
+            }
+
+            if let Some(d) = current_deadline().filter(|d| d.is_expired()) {
+                return Err(format!(
+                    "{}",
+                    d.error(&format!("waiting for the lock of `{}`", path.display()))
+                ));
+            }
+
+            sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+fn open_lock_file(path: &Path) -> Result<File, String> {
+    if let Err(e) = create_dir_all(path) {
+        return Err(format!("failed to create `{}`: {}", path.display(), e));
+    }
+
+    let lock_path = path.join(LOCK_FILE);
+    match OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(&lock_path)
+    {
+        Ok(f) => Ok(f),
+        Err(e) => Err(format!("failed to open `{}`: {}", lock_path.display(), e)),
+    }
+}
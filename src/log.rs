@@ -0,0 +1,248 @@
+use chrono::Utc;
+use serde::Serialize;
+use std::{
+    fs::{create_dir_all, File, OpenOptions},
+    io::{Result as IoResult, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Log file of the current run, shared with forked children
+static FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Whether events are printed to the standard output as JSON objects
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Stage that is currently running and the time it started at
+static STAGE: Mutex<Option<(String, Instant)>> = Mutex::new(None);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Info,
+    Ok,
+    Warning,
+    Error,
+    Failure,
+}
+
+impl Level {
+    fn prefix(&self) -> &'static str {
+        match self {
+            Self::Info => "",
+            Self::Ok => "... OK: ",
+            Self::Warning => "... WARNING: ",
+            Self::Error => "... ERROR: ",
+            Self::Failure => "!!! FAILURE: ",
+        }
+    }
+}
+
+/// A single entry of the `--log-format json` event stream
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Message {
+        level: Level,
+        message: &'a str,
+    },
+    StageStarted {
+        stage: &'a str,
+        message: &'a str,
+    },
+    StageFinished {
+        stage: &'a str,
+        success: bool,
+        duration_ms: u128,
+    },
+    DownloadProgress {
+        url: &'a str,
+        bytes: u64,
+        total: Option<u64>,
+    },
+    Command {
+        command: &'a str,
+        args: &'a [String],
+        status: Option<i32>,
+        duration_ms: u128,
+    },
+    Artifact {
+        format: &'a str,
+        path: &'a Path,
+        size: u64,
+        sha256: &'a str,
+    },
+    Result {
+        command: &'a str,
+        success: bool,
+        log_file: Option<&'a Path>,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+pub fn set_format(format: Format) {
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+/// Whether the standard output is reserved for the JSON event stream
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Creates the log file of this run in `dir`, named after the time and the PID
+pub fn open_file(dir: &Path) -> IoResult<PathBuf> {
+    create_dir_all(dir)?;
+
+    let path = dir.join(format!(
+        "nixos-conjurer-{}-{}.log",
+        Utc::now().format("%Y%m%dT%H%M%SZ"),
+        process::id()
+    ));
+    let file = OpenOptions::new()
+        .append(true)
+        .create_new(true)
+        .open(&path)?;
+    *FILE.lock().unwrap() = Some(file);
+
+    Ok(path)
+}
+
+/// Appends a timestamped line to the log file only
+pub fn write(line: &str) {
+    if let Some(f) = FILE.lock().unwrap().as_mut() {
+        // the console output is still there, so don't fail the build
+        let _ = writeln!(
+            f,
+            "{} {}",
+            Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+            line
+        );
+    }
+}
+
+/// Prints the event to the standard output when the JSON format is used
+pub fn event(event: Event) {
+    if !is_json() {
+        return;
+    }
+
+    let record = Record {
+        timestamp: Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        event,
+    };
+
+    if let Ok(json) = serde_json::to_string(&record) {
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{}", json);
+        let _ = stdout.flush();
+    }
+}
+
+/// Prints the message to the console and the log file
+pub fn message(level: Level, message: &str) {
+    let line = format!("{}{}", level.prefix(), message);
+    write(&line);
+
+    if is_json() {
+        return event(Event::Message { level, message });
+    }
+
+    match level {
+        Level::Info | Level::Ok => println!("{}", line),
+        Level::Warning | Level::Error | Level::Failure => eprintln!("{}", line),
+    }
+}
+
+pub fn info(m: &str) {
+    message(Level::Info, m);
+}
+
+/// Finishes the running stage, if any, and starts the `name` stage
+pub fn stage(name: &str, message: &str) {
+    finish_stage(true);
+
+    write(message);
+    match is_json() {
+        true => event(Event::StageStarted {
+            stage: name,
+            message,
+        }),
+        false => println!("{}", message),
+    }
+
+    *STAGE.lock().unwrap() = Some((name.to_owned(), Instant::now()));
+}
+
+pub fn finish_stage(success: bool) {
+    let stage = STAGE.lock().unwrap().take();
+
+    if let Some((name, started)) = stage {
+        event(Event::StageFinished {
+            stage: &name,
+            success,
+            duration_ms: started.elapsed().as_millis(),
+        });
+    }
+}
+
+/// Reports the progress of a download at most once a second
+pub struct Progress {
+    url: String,
+    total: Option<u64>,
+    reported: Instant,
+}
+
+impl Progress {
+    const INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new(url: &str, total: Option<u64>) -> Self {
+        Self {
+            url: url.to_owned(),
+            total,
+            reported: Instant::now(),
+        }
+    }
+
+    pub fn update(&mut self, bytes: u64) {
+        if self.reported.elapsed() >= Self::INTERVAL {
+            self.report(bytes);
+        }
+    }
+
+    pub fn report(&mut self, bytes: u64) {
+        self.reported = Instant::now();
+
+        event(Event::DownloadProgress {
+            url: &self.url,
+            bytes,
+            total: self.total,
+        });
+    }
+}
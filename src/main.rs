@@ -2,11 +2,20 @@ mod alpine;
 mod app;
 mod archive;
 mod builder;
+mod cache;
+mod cli;
 mod config;
+mod digest;
+mod doctor;
+mod gpg;
 mod http;
+mod limits;
+mod lock;
+mod log;
 mod mount;
 mod nixos;
 mod process;
+mod store;
 
 fn main() {
     if let Err(code) = run_main() {
@@ -17,26 +26,38 @@ fn main() {
 fn run_main() -> Result<(), i32> {
     let args: Vec<String> = std::env::args().collect();
 
-    let app = match crate::app::init_app(&args) {
+    let arguments = match crate::cli::parse(&args) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+
+            usage(&args, crate::app::ErrorCode::CommandLineParserError as i32)
+        }
+    };
+
+    if arguments.command() == crate::cli::Command::Help {
+        usage(&args, 0);
+    }
+
+    let app = match crate::app::init_app(&arguments) {
         Ok(app) => app,
-        Err(e) => match e.code() {
-            crate::app::ErrorCode::CommandLineParserError => usage(&args),
-            _ => {
-                eprintln!("{}", e);
+        Err(e) => {
+            eprintln!("{}", e);
 
-                return Err(e.code() as i32);
-            }
-        },
+            return Err(e.code() as i32);
+        }
     };
 
     if let Err(e) = app.run() {
+        eprintln!("{}", e);
+
         return Err(e.code() as i32);
     }
 
     Ok({})
 }
 
-fn usage(args: &Vec<String>) -> ! {
+fn usage(args: &[String], code: i32) -> ! {
     let bin_path = std::path::PathBuf::from(&args[0]);
     let bin_name = match bin_path.file_name() {
         Some(name) => match name.to_str() {
@@ -46,7 +67,12 @@ fn usage(args: &Vec<String>) -> ! {
         None => "nixos-conjurer",
     };
 
-    eprintln!("Usage: {} <configuration-path>", bin_name);
+    let usage = crate::cli::usage(bin_name);
+    if code == 0 {
+        println!("{}", usage);
+    } else {
+        eprintln!("{}", usage);
+    }
 
-    std::process::exit(1);
+    std::process::exit(code);
 }
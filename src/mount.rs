@@ -1,8 +1,9 @@
-use std::path::Path;
+use std::{fs::read_to_string, path::Path};
 
 use nix::{
     errno::Errno,
     mount::{mount as nix_mount, MsFlags},
+    sys::statvfs::{statvfs, FsFlags},
 };
 
 pub fn bind<P1: AsRef<Path>, P2: AsRef<Path>>(source: P1, target: P2) -> Result<(), Errno> {
@@ -15,6 +16,54 @@ pub fn bind<P1: AsRef<Path>, P2: AsRef<Path>>(source: P1, target: P2) -> Result<
     )
 }
 
+/// Bind-mounts `source` to `target` recursively and read-only
+pub fn bind_read_only<P1: AsRef<Path>, P2: AsRef<Path>>(
+    source: P1,
+    target: P2,
+) -> Result<(), Errno> {
+    bind(&source, &target)?;
+
+    let target = match target.as_ref().canonicalize() {
+        Ok(t) => t,
+        Err(e) => return Err(errno(e)),
+    };
+    let mountinfo = match read_to_string("/proc/self/mountinfo") {
+        Ok(m) => m,
+        Err(e) => return Err(errno(e)),
+    };
+
+    // the mount point is the fifth field, with whitespace escaped as octal
+    let mount_points = mountinfo
+        .lines()
+        .filter_map(|l| l.split(' ').nth(4))
+        .map(unescape)
+        .filter(|p| Path::new(p).starts_with(&target));
+    for p in mount_points {
+        // the flags locked by the user namespace must be kept on remount
+        let current = statvfs(p.as_str())?.flags();
+        let mut flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+        for (f, m) in [
+            (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+            (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+            (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        ] {
+            if current.contains(f) {
+                flags |= m;
+            }
+        }
+
+        mount(
+            None as Option<&str>,
+            &p,
+            None as Option<&str>,
+            Some(flags),
+            None as Option<&str>,
+        )?;
+    }
+
+    Ok(())
+}
+
 pub fn mount<
     P1: AsRef<Path> + ?Sized,
     P2: AsRef<Path>,
@@ -50,3 +99,61 @@ pub fn mount<
 
     Ok({})
 }
+
+fn errno(e: std::io::Error) -> Errno {
+    match e.raw_os_error() {
+        Some(e) => Errno::from_i32(e),
+        None => Errno::EIO,
+    }
+}
+
+/// Decodes the `\NNN` octal escapes of a path in `/proc/self/mountinfo`
+fn unescape(path: &str) -> String {
+    let mut result = vec![];
+    let bytes = path.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = match bytes.get(i + 1..i + 4) {
+            Some(o) if bytes[i] == b'\\' => std::str::from_utf8(o)
+                .ok()
+                .and_then(|o| u8::from_str_radix(o, 8).ok()),
+            _ => None,
+        };
+
+        match code {
+            Some(c) => {
+                result.push(c);
+                i += 4;
+            }
+            None => {
+                result.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescape_decodes_octal_escapes() {
+        assert_eq!(unescape("/mnt/a\\040b"), "/mnt/a b");
+        assert_eq!(unescape("\\011tab\\012line"), "\ttab\nline");
+        assert_eq!(unescape("back\\134slash"), "back\\slash");
+        assert_eq!(unescape("/caf\\303\\251"), "/café");
+    }
+
+    #[test]
+    fn unescape_keeps_everything_else() {
+        assert_eq!(unescape("/nix/store"), "/nix/store");
+        assert_eq!(unescape("/a\\b"), "/a\\b");
+        assert_eq!(unescape("/a\\09"), "/a\\09");
+        assert_eq!(unescape("/trailing\\"), "/trailing\\");
+        assert_eq!(unescape("/short\\04"), "/short\\04");
+        assert_eq!(unescape("/big\\400"), "/big\\400");
+    }
+}
@@ -1,6 +1,8 @@
-use std::fmt::Display;
+use std::{fmt::Display, path::Path};
 
-use crate::process::run_command_checked;
+use sha2::Sha256;
+
+use crate::{http, process::run_command_checked};
 
 #[derive(Debug)]
 pub struct Error {
@@ -21,8 +23,8 @@ impl Display for Error {
 
 impl std::error::Error for Error {}
 
-pub fn add_channel<C: AsRef<str>>(channel: C) -> Result<(), Error> {
-    let args: Vec<&str> = vec!["--add", channel.as_ref()];
+pub fn add_channel<C: AsRef<str>, N: AsRef<str>>(channel: C, name: N) -> Result<(), Error> {
+    let args: Vec<&str> = vec!["--add", channel.as_ref(), name.as_ref()];
 
     if let Err(e) = run_command_checked("nix-channel", &args) {
         return Err(Error::new(format!("failed to add the Nix channel: {}", e)));
@@ -32,11 +34,15 @@ pub fn add_channel<C: AsRef<str>>(channel: C) -> Result<(), Error> {
 }
 
 pub fn update_channels() -> Result<(), Error> {
-    if let Err(e) = std::fs::remove_dir("/nix/var/nix/profiles/default") {
-        return Err(Error::new(format!(
-            "failed to remove default profile directory: {}",
-            e
-        )));
+    // the directory is gone after a previous (failed) update attempt
+    let default_profile = std::path::Path::new("/nix/var/nix/profiles/default");
+    if default_profile.is_dir() && !default_profile.is_symlink() {
+        if let Err(e) = std::fs::remove_dir(default_profile) {
+            return Err(Error::new(format!(
+                "failed to remove default profile directory: {}",
+                e
+            )));
+        }
     }
 
     if let Err(e) = run_command_checked("nix-channel", &["--update"]) {
@@ -59,3 +65,86 @@ pub fn install<P: AsRef<str>>(packages: Vec<P>) -> Result<(), Error> {
 
     Ok({})
 }
+
+/// Deletes old generations and unused store paths until `max_freed` bytes are freed
+pub fn collect_garbage(max_freed: Option<u64>) -> Result<(), Error> {
+    let mut args = vec![String::from("--delete-old")];
+    if let Some(m) = max_freed {
+        args.push(String::from("--max-freed"));
+        args.push(m.to_string());
+    }
+
+    if let Err(e) = run_command_checked("nix-collect-garbage", &args) {
+        return Err(Error::new(format!(
+            "failed to collect the Nix store garbage: {}",
+            e
+        )));
+    }
+
+    Ok(())
+}
+
+/// Returns the nixpkgs revision of `channel` from the first mirror that serves it
+pub fn channel_revision<M: AsRef<str>>(
+    client: &http::Client,
+    mirrors: &[M],
+    channel: &str,
+) -> Result<String, Error> {
+    match channel_revision_impl(client, mirrors, channel) {
+        Ok(r) => Ok(r.trim().to_owned()),
+        Err(e) => Err(Error::new(format!(
+            "failed to get the revision of the `{}` channel: {}",
+            channel, e
+        ))),
+    }
+}
+
+fn channel_revision_impl<M: AsRef<str>>(
+    client: &http::Client,
+    mirrors: &[M],
+    channel: &str,
+) -> http::Result<String> {
+    let req = http::GetRequest::mirrored(mirrors, &format!("{}/git-revision", channel))?;
+    let revision = client.get(req)?.as_text()?;
+
+    Ok(revision)
+}
+
+/// Downloads a pinned nixpkgs tarball and verifies its SHA-256 checksum
+pub fn download_nixpkgs(
+    client: &http::Client,
+    url: &str,
+    sha256: &str,
+    destination: &Path,
+) -> Result<(), Error> {
+    let download = match download_nixpkgs_impl(client, url, destination) {
+        Ok(d) => d,
+        Err(e) => {
+            return Err(Error::new(format!(
+                "failed to download the nixpkgs tarball `{}`: {}",
+                url, e
+            )))
+        }
+    };
+
+    if download.digest() != sha256 {
+        return Err(Error::new(format!(
+            "SHA-256 checksum of the nixpkgs tarball doesn't match. Expected `{}`, got `{}`",
+            sha256,
+            download.digest()
+        )));
+    }
+
+    Ok(())
+}
+
+fn download_nixpkgs_impl(
+    client: &http::Client,
+    url: &str,
+    destination: &Path,
+) -> http::Result<http::Download> {
+    let req = http::GetRequest::new(url)?;
+    let download = client.download::<Sha256>(req, destination)?;
+
+    Ok(download)
+}
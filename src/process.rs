@@ -1,18 +1,64 @@
+use crate::log::{self, Event};
 use ipc_channel::ipc::{channel, IpcReceiver, IpcSender};
 use nix::{
+    errno::Errno,
+    libc::{self, c_int},
     sys::{
-        signal::{kill, Signal},
+        signal::{kill, killpg, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
         wait::{waitpid, WaitPidFlag, WaitStatus},
     },
     unistd::{fork, getpid, ForkResult, Pid},
 };
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::{HashMap, VecDeque},
     ffi::OsStr,
     fmt::Display,
-    process::{exit, Command, Output},
+    io::{BufRead, BufReader, Read, Result as IoResult},
+    os::unix::process::CommandExt,
+    path::Path,
+    process::{exit, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    thread::{self, sleep},
+    time::{Duration, Instant},
 };
 
+/// Number of the last output lines of a command kept for error messages
+const TAIL_LINES: usize = 40;
+
+/// Longer lines are truncated when kept in the tail
+const TAIL_LINE_LENGTH: usize = 1024;
+
+/// Time a cancelled child is given to exit before it is killed
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Set once SIGTERM, or SIGINT outside of interactive sessions, is received
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether SIGINT cancels the run, it belongs to the shell in interactive sessions
+static CANCEL_ON_INTERRUPT: AtomicBool = AtomicBool::new(true);
+
+/// Whether a repeated signal terminates the process, only the top-level one does
+static TERMINATE_ON_REPEAT: AtomicBool = AtomicBool::new(false);
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+/// Extra variables passed to every executed command
+static ENVIRONMENT: Mutex<Vec<(String, String)>> = Mutex::new(vec![]);
+
+static RUN_DEADLINE: Mutex<Option<Deadline>> = Mutex::new(None);
+
+/// Time limit of the running stage, shared with forked children
+static STAGE_DEADLINE: OnceLock<&'static SharedStage> = OnceLock::new();
+
+/// Origin of the times kept in `STAGE_DEADLINE`, inherited by forked children
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+static STAGE_TIMEOUTS: Mutex<Vec<(String, Duration)>> = Mutex::new(vec![]);
+
 macro_rules! error {
     ($($msg:expr),+) => {
         Error { msg: format!($($msg),+) }
@@ -40,7 +86,287 @@ impl Display for Error {
 
 impl std::error::Error for Error {}
 
+/// Controls how much of the executed commands is printed
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    /// Nothing but errors
+    Quiet,
+    /// Standard error of commands as it is produced
+    Normal,
+    /// Commands themselves and both of their output streams
+    Verbose,
+}
+
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        v if v == Verbosity::Quiet as u8 => Verbosity::Quiet,
+        v if v == Verbosity::Verbose as u8 => Verbosity::Verbose,
+        _ => Verbosity::Normal,
+    }
+}
+
+/// Point in time a process must be finished by
+#[derive(Clone)]
+pub struct Deadline {
+    at: Instant,
+    limit: Duration,
+    scope: String,
+}
+
+impl Deadline {
+    fn new(limit: Duration, scope: String) -> Self {
+        Self {
+            at: Instant::now() + limit,
+            limit,
+            scope,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.at
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+
+    pub fn error(&self, what: &str) -> Error {
+        error!(
+            "{} exceeded the {}s time limit of {}",
+            what,
+            self.limit.as_secs(),
+            self.scope
+        )
+    }
+}
+
+/// Stage time limit in memory shared with forked children, so parents enforce it too
+struct SharedStage {
+    /// Index of the stage in `STAGE_TIMEOUTS` plus one, zero if there's none
+    index: AtomicUsize,
+    /// Milliseconds since `EPOCH` the stage must be finished by
+    deadline: AtomicU64,
+}
+
+/// Starts counting the `overall` time limit of the run
+pub fn set_timeouts(
+    overall: Option<Duration>,
+    stages: HashMap<String, Duration>,
+) -> ProcResult<()> {
+    EPOCH.get_or_init(Instant::now);
+    *RUN_DEADLINE.lock().unwrap() = overall.map(|t| Deadline::new(t, "the run".to_owned()));
+    *STAGE_TIMEOUTS.lock().unwrap() = stages.into_iter().collect();
+
+    if STAGE_TIMEOUTS.lock().unwrap().is_empty() || STAGE_DEADLINE.get().is_some() {
+        return Ok(());
+    }
+
+    let shared = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            std::mem::size_of::<SharedStage>(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if shared == libc::MAP_FAILED {
+        return err!(
+            "failed to map the memory for stage time limits: {}",
+            Errno::last()
+        );
+    }
+
+    // the anonymous mapping is zeroed, which is a valid value without a stage
+    let _ = STAGE_DEADLINE.set(unsafe { &*(shared as *const SharedStage) });
+
+    Ok(())
+}
+
+/// Starts counting the time limit of the `name` stage, if it has one
+pub fn enter_stage(name: &str) -> ProcResult<()> {
+    if let Some(d) = RUN_DEADLINE.lock().unwrap().as_ref() {
+        if d.is_expired() {
+            return Err(d.error(&format!("the `{}` stage", name)));
+        }
+    }
+
+    let shared = match STAGE_DEADLINE.get() {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+
+    let index = STAGE_TIMEOUTS
+        .lock()
+        .unwrap()
+        .iter()
+        .position(|(s, _)| s == name);
+
+    match index {
+        Some(i) => {
+            let limit = STAGE_TIMEOUTS.lock().unwrap()[i].1;
+            let at = Instant::now() + limit - *EPOCH.get().unwrap();
+            shared
+                .deadline
+                .store(at.as_millis() as u64, Ordering::Relaxed);
+            shared.index.store(i + 1, Ordering::Relaxed);
+        }
+        None => shared.index.store(0, Ordering::Relaxed),
+    }
+
+    Ok(())
+}
+
+fn leave_stage() {
+    if let Some(s) = STAGE_DEADLINE.get() {
+        s.index.store(0, Ordering::Relaxed);
+    }
+}
+
+fn stage_deadline() -> Option<Deadline> {
+    let shared = STAGE_DEADLINE.get()?;
+    let index = shared.index.load(Ordering::Relaxed).checked_sub(1)?;
+    let at = Duration::from_millis(shared.deadline.load(Ordering::Relaxed));
+    let (name, limit) = STAGE_TIMEOUTS.lock().unwrap().get(index)?.clone();
+
+    Some(Deadline {
+        at: *EPOCH.get()? + at,
+        limit,
+        scope: format!("the `{}` stage", name),
+    })
+}
+
+/// Returns the earliest of the run and the current stage deadlines
+pub fn current_deadline() -> Option<Deadline> {
+    let run = RUN_DEADLINE.lock().unwrap().clone();
+    let stage = stage_deadline();
+
+    match (run, stage) {
+        (Some(r), Some(s)) if s.at < r.at => Some(s),
+        (Some(r), _) => Some(r),
+        (None, s) => s,
+    }
+}
+
+pub struct Output {
+    pub status: ExitStatus,
+    /// Complete standard output
+    pub stdout: Vec<u8>,
+    /// Last lines of both output streams, decoded as UTF-8
+    pub tail: String,
+}
+
+/// Bounded buffer with the last output lines of a command
+struct Tail {
+    lines: VecDeque<String>,
+}
+
+impl Tail {
+    fn new() -> Self {
+        Self {
+            lines: VecDeque::with_capacity(TAIL_LINES),
+        }
+    }
+
+    fn push(&mut self, line: &str) {
+        if self.lines.len() == TAIL_LINES {
+            self.lines.pop_front();
+        }
+
+        let line = match line.char_indices().nth(TAIL_LINE_LENGTH) {
+            Some((i, _)) => format!("{}...", &line[..i]),
+            None => line.to_owned(),
+        };
+        self.lines.push_back(line);
+    }
+
+    fn into_string(self) -> String {
+        Vec::from(self.lines).join("\n")
+    }
+}
+
+extern "C" fn handle_signal(signal: c_int) {
+    if signal != Signal::SIGTERM as c_int && !CANCEL_ON_INTERRUPT.load(Ordering::Relaxed) {
+        return;
+    }
+
+    // the cancellation got stuck, so fall back to the default action
+    if CANCELLED.swap(true, Ordering::Relaxed) && TERMINATE_ON_REPEAT.load(Ordering::Relaxed) {
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+}
+
+/// Makes SIGINT and SIGTERM cancel the run, a second signal terminates the process
+pub fn install_signal_handlers(cancel_on_interrupt: bool) -> ProcResult<()> {
+    CANCEL_ON_INTERRUPT.store(cancel_on_interrupt, Ordering::Relaxed);
+    TERMINATE_ON_REPEAT.store(true, Ordering::Relaxed);
+
+    // no SA_RESTART, so that `waitpid` is interrupted by the signal
+    let action = SigAction::new(
+        SigHandler::Handler(handle_signal),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+
+    for signal in [Signal::SIGINT, Signal::SIGTERM] {
+        if let Err(e) = unsafe { sigaction(signal, &action) } {
+            return err!("failed to install the {} signal handler: {}", signal, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Lets the worker be terminated, the init of its PID namespace handles cancellation
+fn restore_default_signal_handlers() {
+    let action = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+
+    let _ = unsafe { sigaction(Signal::SIGTERM, &action) };
+    if CANCEL_ON_INTERRUPT.load(Ordering::Relaxed) {
+        let _ = unsafe { sigaction(Signal::SIGINT, &action) };
+    }
+}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::Relaxed)
+}
+
+pub fn set_environment(environment: Vec<(String, String)>) {
+    *ENVIRONMENT.lock().unwrap() = environment;
+}
+
+/// Check of a running child, called at the given interval
+type Watch<'a> = (Duration, &'a mut dyn FnMut() -> Result<(), String>);
+
 pub fn run_forked<T: Serialize + for<'de> Deserialize<'de>, F: Fn() -> T>(f: F) -> ProcResult<T> {
+    fork_child(f, None)
+}
+
+/// Runs `f` like `run_forked`, killing the child once `watch` fails
+pub fn run_forked_watched<
+    T: Serialize + for<'de> Deserialize<'de>,
+    F: Fn() -> T,
+    W: FnMut() -> Result<(), String>,
+>(
+    f: F,
+    interval: Duration,
+    mut watch: W,
+) -> ProcResult<T> {
+    fork_child(f, Some((interval, &mut watch)))
+}
+
+fn fork_child<T: Serialize + for<'de> Deserialize<'de>, F: Fn() -> T>(
+    f: F,
+    watch: Option<Watch>,
+) -> ProcResult<T> {
     let (tx, rx) = match channel() {
         Ok(c) => c,
         Err(e) => {
@@ -51,15 +377,37 @@ pub fn run_forked<T: Serialize + for<'de> Deserialize<'de>, F: Fn() -> T>(f: F)
         }
     };
 
+    if is_cancelled() {
+        return err!("the run was cancelled");
+    }
+
+    // stages don't span processes, the child reports its own ones
+    log::finish_stage(true);
+    leave_stage();
+
     match unsafe { fork() } {
-        Ok(ForkResult::Parent { child }) => wait_for_child(rx, child),
+        Ok(ForkResult::Parent { child }) => {
+            let result = wait_for_child(rx, child, watch);
+            leave_stage();
+
+            result
+        }
         Ok(ForkResult::Child) => run_child(tx, f),
         Err(e) => err!("failed to fork a child process: {}", e),
     }
 }
 
 fn run_child<T: Serialize, F: Fn() -> T>(tx: IpcSender<ProcResult<T>>, f: F) -> ! {
-    if let Err(_) = tx.send(Ok(f())) {
+    TERMINATE_ON_REPEAT.store(false, Ordering::Relaxed);
+
+    // don't outlive the parent, even if it gets killed: when the child is
+    // the init of a PID namespace, the whole namespace goes away with it
+    unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+
+    let result = f();
+    log::finish_stage(true);
+
+    if let Err(_) = tx.send(Ok(result)) {
         exit(1);
     }
 
@@ -69,9 +417,63 @@ fn run_child<T: Serialize, F: Fn() -> T>(tx: IpcSender<ProcResult<T>>, f: F) ->
 fn wait_for_child<T: for<'de> Deserialize<'de> + Serialize>(
     rx: IpcReceiver<ProcResult<T>>,
     child_pid: Pid,
+    mut watch: Option<Watch>,
 ) -> ProcResult<T> {
+    // the stages the child enters are only known once they start
+    let limited = RUN_DEADLINE.lock().unwrap().is_some() || STAGE_DEADLINE.get().is_some();
+    let mut timed_out = None;
+    let mut deadline = None;
+    let mut watched = Instant::now();
+    let mut failed = None;
+
     loop {
-        match waitpid(child_pid, Some(WaitPidFlag::WUNTRACED)) {
+        // forward the cancellation and wait for the child to exit, killing
+        // it when the grace period is over
+        if is_cancelled() && deadline.is_none() {
+            let _ = kill(child_pid, Signal::SIGTERM);
+            deadline = Some(Instant::now() + CANCEL_GRACE_PERIOD);
+        }
+
+        // a killed namespace init takes the whole namespace down with it
+        if timed_out.is_none() {
+            timed_out = current_deadline().filter(|d| d.is_expired());
+            if timed_out.is_some() {
+                let _ = kill(child_pid, Signal::SIGKILL);
+            }
+        }
+
+        if let Some((interval, check)) = watch.as_mut() {
+            if failed.is_none() && deadline.is_none() && watched.elapsed() >= *interval {
+                if let Err(e) = check() {
+                    let _ = kill(child_pid, Signal::SIGKILL);
+                    failed = Some(e);
+                }
+                watched = Instant::now();
+            }
+        }
+
+        let flags = match deadline.is_some() || limited || watch.is_some() {
+            true => WaitPidFlag::WUNTRACED | WaitPidFlag::WNOHANG,
+            false => WaitPidFlag::WUNTRACED,
+        };
+
+        match waitpid(child_pid, Some(flags)) {
+            Ok(WaitStatus::StillAlive) => {
+                if deadline.is_some_and(|d| Instant::now() >= d) {
+                    let _ = kill(child_pid, Signal::SIGKILL);
+                }
+                sleep(Duration::from_millis(100));
+            }
+            Err(Errno::EINTR) => {}
+            Ok(WaitStatus::Exited(..) | WaitStatus::Signaled(..)) if timed_out.is_some() => {
+                return Err(timed_out.unwrap().error("the child process"));
+            }
+            Ok(WaitStatus::Exited(..) | WaitStatus::Signaled(..)) if failed.is_some() => {
+                return err!("{}", failed.unwrap());
+            }
+            Ok(WaitStatus::Exited(..) | WaitStatus::Signaled(..)) if deadline.is_some() => {
+                return err!("the run was cancelled");
+            }
             Ok(WaitStatus::Exited(_, 0)) => match read_child_status(rx) {
                 r => return r,
             },
@@ -111,6 +513,97 @@ fn wait_for_child<T: for<'de> Deserialize<'de> + Serialize>(
     }
 }
 
+/// Runs `f` in a worker child as the init process of a PID namespace
+pub fn run_init<T: Serialize + for<'de> Deserialize<'de>, F: Fn() -> T>(f: F) -> ProcResult<T> {
+    let (tx, rx) = match channel() {
+        Ok(c) => c,
+        Err(e) => {
+            return err!(
+                "failed to create a channel to communicate with the build worker: {}",
+                e
+            )
+        }
+    };
+
+    log::finish_stage(true);
+
+    let worker = match unsafe { fork() } {
+        Ok(ForkResult::Parent { child }) => child,
+        Ok(ForkResult::Child) => {
+            restore_default_signal_handlers();
+            run_child(tx, f)
+        }
+        Err(e) => return err!("failed to fork the build worker: {}", e),
+    };
+
+    let mut forwarded = false;
+    let status = loop {
+        if is_cancelled() && !forwarded {
+            let _ = kill(worker, Signal::SIGTERM);
+            forwarded = true;
+        }
+
+        // any child is waited for, since orphans are re-parented to init
+        match waitpid(None, None) {
+            Ok(s @ (WaitStatus::Exited(pid, _) | WaitStatus::Signaled(pid, _, _)))
+                if pid == worker =>
+            {
+                break s
+            }
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(e) => return err!("failed to wait for the build worker to complete: {}", e),
+        }
+    };
+
+    let result = match status {
+        _ if forwarded => err!("the run was cancelled"),
+        WaitStatus::Exited(_, 0) => read_child_status(rx),
+        WaitStatus::Exited(pid, status) => match read_child_status(rx) {
+            Err(s) => err!(
+                "build worker `{}` returned non-zero status {}: {}",
+                pid,
+                status,
+                s.msg
+            ),
+            Ok(_) => err!("build worker `{}` returned non-zero status {}", pid, status),
+        },
+        _ => err!("build worker was terminated by a signal: {:?}", status),
+    };
+
+    match kill_stray_processes() {
+        0 => result,
+        n if result.is_ok() => err!(
+            "{} stray process(es) were still running after the build worker exited",
+            n
+        ),
+        _ => result,
+    }
+}
+
+/// Kills the processes left in the PID namespace and returns their number
+fn kill_stray_processes() -> usize {
+    // reap the processes that have already exited
+    loop {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => break,
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(_) => return 0,
+        }
+    }
+
+    // signals everything in the namespace, except the init process itself
+    let _ = kill(Pid::from_raw(-1), Signal::SIGKILL);
+
+    let mut killed = 0;
+    loop {
+        match waitpid(None, None) {
+            Ok(_) => killed += 1,
+            Err(Errno::EINTR) => {}
+            Err(_) => return killed,
+        }
+    }
+}
+
 fn read_child_status<T: for<'de> Deserialize<'de> + Serialize>(
     rx: IpcReceiver<ProcResult<T>>,
 ) -> ProcResult<T> {
@@ -120,13 +613,16 @@ fn read_child_status<T: for<'de> Deserialize<'de> + Serialize>(
     }
 }
 
-pub fn run_command<C: AsRef<str>, A: AsRef<OsStr>, I: IntoIterator<Item = A>>(
+/// Builds the command with the build root environment, unless it runs on the host
+fn build_command<C: AsRef<str>, A: AsRef<OsStr>, I: IntoIterator<Item = A>>(
     command: C,
     args: I,
-) -> ProcResult<Output> {
-    match Command::new(command.as_ref())
-        .args(args)
-        .env(
+    host: bool,
+) -> Command {
+    let mut cmd = Command::new(command.as_ref());
+    cmd.args(args);
+    if !host {
+        cmd.env(
             "PATH",
             "/nix/var/nix/profiles/default/bin:/usr/sbin:/usr/bin:/sbin:/bin",
         )
@@ -135,9 +631,199 @@ pub fn run_command<C: AsRef<str>, A: AsRef<OsStr>, I: IntoIterator<Item = A>>(
         .env("TMP", "/tmp")
         .env("TEMP", "/tmp")
         .env("HOME", "/root")
-        .output()
+        .envs(ENVIRONMENT.lock().unwrap().iter().cloned());
+    }
+
+    // the environment is left out, since proxy URLs may contain credentials
+    let mut line = format!("+ {:?}", cmd.get_program());
+    for arg in cmd.get_args() {
+        line.push_str(&format!(" {:?}", arg));
+    }
+    if verbosity() == Verbosity::Verbose {
+        print_output(&line);
+    }
+    log::write(&line);
+
+    cmd
+}
+
+/// Runs the command, streaming its output and keeping the last lines for errors
+pub fn run_command<C: AsRef<str>, A: AsRef<OsStr>, I: IntoIterator<Item = A>>(
+    command: C,
+    args: I,
+) -> ProcResult<Output> {
+    run(command, args, false)
+}
+
+/// Runs the command like `run_command`, but with the host environment
+pub fn run_host_command<C: AsRef<str>, A: AsRef<OsStr>, I: IntoIterator<Item = A>>(
+    command: C,
+    args: I,
+) -> ProcResult<Output> {
+    run(command, args, true)
+}
+
+fn run<C: AsRef<str>, A: AsRef<OsStr>, I: IntoIterator<Item = A>>(
+    command: C,
+    args: I,
+    host: bool,
+) -> ProcResult<Output> {
+    let mut cmd = build_command(&command, args, host);
+    let started = Instant::now();
+    // a process group of its own lets the whole process tree be killed
+    let mut child = match cmd
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
     {
-        Ok(o) => Ok(o),
+        Ok(c) => c,
+        Err(e) => {
+            return err!(
+                "failed to execute the `{}` command: {}",
+                command.as_ref(),
+                e
+            )
+        }
+    };
+
+    let name = Path::new(command.as_ref())
+        .file_name()
+        .map_or(command.as_ref().into(), |n| n.to_string_lossy());
+    let verbosity = verbosity();
+    let tail = Mutex::new(Tail::new());
+    let mut stdout = vec![];
+    let deadline = current_deadline();
+    let group = Pid::from_raw(child.id() as i32);
+    let finished = AtomicBool::new(false);
+    let timed_out = AtomicBool::new(false);
+
+    let (child_stdout, child_stderr) = (child.stdout.take(), child.stderr.take());
+    let result = thread::scope(|s| {
+        // the command doesn't get the signals sent to our process group
+        s.spawn(|| {
+            while !finished.load(Ordering::Relaxed) {
+                if is_cancelled() {
+                    let _ = killpg(group, Signal::SIGKILL);
+                    break;
+                }
+                if deadline.as_ref().is_some_and(|d| d.is_expired()) {
+                    let _ = killpg(group, Signal::SIGKILL);
+                    timed_out.store(true, Ordering::Relaxed);
+                    break;
+                }
+                sleep(Duration::from_millis(100));
+            }
+        });
+
+        if let Some(pipe) = child_stderr {
+            s.spawn(|| {
+                for_each_line(pipe, |l| {
+                    let line = String::from_utf8_lossy(l);
+                    let line = line.trim_end();
+                    let prefixed = format!("[{}] {}", name, line);
+                    if verbosity >= Verbosity::Normal {
+                        eprintln!("{}", prefixed);
+                    }
+                    log::write(&prefixed);
+                    tail.lock().unwrap().push(line);
+                })
+            });
+        }
+
+        if let Some(pipe) = child_stdout {
+            for_each_line(pipe, |l| {
+                stdout.extend_from_slice(l);
+
+                let line = String::from_utf8_lossy(l);
+                let line = line.trim_end();
+                let prefixed = format!("[{}] {}", name, line);
+                if verbosity == Verbosity::Verbose {
+                    print_output(&prefixed);
+                }
+                log::write(&prefixed);
+                tail.lock().unwrap().push(line);
+            });
+        }
+
+        let result = child.wait();
+        finished.store(true, Ordering::Relaxed);
+
+        result
+    });
+    command_event(&cmd, &result, started);
+
+    if timed_out.load(Ordering::Relaxed) {
+        let what = format!("the `{}` command", command.as_ref());
+
+        return Err(deadline.unwrap().error(&what));
+    }
+
+    if is_cancelled() {
+        return err!("the run was cancelled");
+    }
+
+    match result {
+        Ok(status) => Ok(Output {
+            status,
+            stdout,
+            tail: tail.into_inner().unwrap().into_string(),
+        }),
+        Err(e) => err!(
+            "failed to wait for the `{}` command: {}",
+            command.as_ref(),
+            e
+        ),
+    }
+}
+
+/// Prints the command output, unless the standard output is the JSON event stream
+fn print_output(line: &str) {
+    match log::is_json() {
+        true => eprintln!("{}", line),
+        false => println!("{}", line),
+    }
+}
+
+fn command_event(cmd: &Command, result: &IoResult<ExitStatus>, started: Instant) {
+    let args: Vec<String> = cmd
+        .get_args()
+        .map(|a| a.to_string_lossy().into_owned())
+        .collect();
+
+    log::event(Event::Command {
+        command: &cmd.get_program().to_string_lossy(),
+        args: &args,
+        status: result.as_ref().ok().and_then(|s| s.code()),
+        duration_ms: started.elapsed().as_millis(),
+    });
+}
+
+fn for_each_line<R: Read, F: FnMut(&[u8])>(reader: R, mut f: F) {
+    let mut reader = BufReader::new(reader);
+    let mut line = vec![];
+
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => f(&line),
+        }
+    }
+}
+
+pub fn run_interactive<C: AsRef<str>, A: AsRef<OsStr>, I: IntoIterator<Item = A>>(
+    command: C,
+    args: I,
+) -> ProcResult<ExitStatus> {
+    let mut cmd = build_command(&command, args, false);
+    let started = Instant::now();
+    let result = cmd.status();
+    command_event(&cmd, &result, started);
+
+    match result {
+        Ok(s) => Ok(s),
         Err(e) => err!(
             "failed to execute the `{}` command: {}",
             command.as_ref(),
@@ -150,7 +836,19 @@ pub fn run_command_checked<C: AsRef<str>, A: AsRef<OsStr>, I: IntoIterator<Item
     command: C,
     args: I,
 ) -> ProcResult<Output> {
-    let result = match run_command(&command, args) {
+    check(&command, run_command(&command, args))
+}
+
+/// Runs the command like `run_command_checked`, but with the host environment
+pub fn run_host_command_checked<C: AsRef<str>, A: AsRef<OsStr>, I: IntoIterator<Item = A>>(
+    command: C,
+    args: I,
+) -> ProcResult<Output> {
+    check(&command, run_host_command(&command, args))
+}
+
+fn check<C: AsRef<str>>(command: C, result: ProcResult<Output>) -> ProcResult<Output> {
+    let result = match result {
         Ok(o) => o,
         Err(e) => {
             return err!(
@@ -166,8 +864,9 @@ pub fn run_command_checked<C: AsRef<str>, A: AsRef<OsStr>, I: IntoIterator<Item
     }
 
     err!(
-        "the `{}` command returned non-zero status:\n{:?}",
+        "the `{}` command returned non-zero status {}:\n{}",
         command.as_ref(),
-        result
+        result.status,
+        result.tail
     )
 }
@@ -0,0 +1,39 @@
+use crate::process::{current_deadline, is_cancelled};
+use std::{collections::HashSet, io::ErrorKind, os::unix::fs::MetadataExt, path::Path};
+use walkdir::WalkDir;
+
+/// Returns the disk space used by the files in `path`, counting hard links once
+pub fn disk_usage(path: &Path) -> std::io::Result<u64> {
+    let deadline = current_deadline();
+    let mut seen = HashSet::new();
+    let mut size = 0;
+
+    for entry in WalkDir::new(path).same_file_system(true) {
+        if is_cancelled() {
+            return Err(std::io::Error::other("the run was cancelled"));
+        }
+
+        if let Some(d) = deadline.as_ref().filter(|d| d.is_expired()) {
+            return Err(std::io::Error::other(d.error("measuring the disk usage")));
+        }
+
+        // files removed while walking, e.g. by a running build, aren't counted
+        let metadata = match entry.and_then(|e| e.metadata()) {
+            Ok(m) => m,
+            Err(e)
+                if e.io_error()
+                    .is_some_and(|e| e.kind() == ErrorKind::NotFound) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if metadata.nlink() > 1 && !seen.insert((metadata.dev(), metadata.ino())) {
+            continue;
+        }
+
+        size += metadata.blocks() * 512;
+    }
+
+    Ok(size)
+}